@@ -0,0 +1,2245 @@
+use futures::future::{Either, Future, Loop};
+use futures::sink::Sink;
+use futures::sync::oneshot;
+use std::collections::{HashMap, HashSet};
+use std::sync::*;
+use std::time::Instant;
+
+use crate::clock::{Clock, SystemClock};
+use crate::condition::{Condition, Context, TimeWindow};
+use crate::confirm::{Confirmation, Source};
+use crate::debounce::Debounce;
+use crate::door::{self, Combine, Door, Doors};
+use crate::hass::{self, Hass};
+use crate::mqtt::{self, Delivery, OpCode, QoS, TopicStates};
+use crate::notify::{self, Notification};
+use crate::payload::{self, Payload};
+use crate::persist::{Saved, StateFile};
+use crate::plan::{Action, Plan};
+use crate::report::{Outcome, ShutdownReport};
+use crate::snapshot::{self, Capture, Snapshot};
+use crate::state_machine::{Effect, Event, State, StateMachine};
+
+const MAX_SAVED_DELAY: std::time::Duration =
+    std::time::Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+#[derive(Clone)]
+pub struct ShutdownMessage {
+    topic: String,
+    payload: Payload,
+    delivery: Delivery,
+    confirmation: Option<Confirmation>,
+    conditions: Vec<Condition>,
+}
+
+impl ShutdownMessage {
+    /// `value` may refer to `{reason}`, `{timestamp}`, `{zone}` and `{plan_remaining}`, the
+    /// seconds until the plan the message belongs to is done, i.e. its last stage is due.
+    pub fn new(topic: &str, value: &str) -> Self {
+        Self::with_payload(topic, Payload::Text(value.to_string()))
+    }
+
+    /// A JSON payload, strings in it may refer to the same placeholders as in `new`. A string
+    /// that is nothing but a placeholder takes its type, e.g. `"{plan_remaining}"` is a
+    /// number.
+    pub fn json(topic: &str, value: serde_json::Value) -> Self {
+        Self::with_payload(topic, Payload::Json(value))
+    }
+
+    fn with_payload(topic: &str, payload: Payload) -> Self {
+        Self {
+            topic: topic.to_string(),
+            payload,
+            delivery: Delivery::default(),
+            confirmation: None,
+            conditions: vec![],
+        }
+    }
+
+    /// Only sends the message if the condition holds when it is due.
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// Checks that the device followed the message and publishes it again if it did not.
+    pub fn confirm(mut self, confirmation: Confirmation) -> Self {
+        self.confirmation = Some(confirmation);
+        self
+    }
+
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.delivery.qos = qos;
+        self
+    }
+
+    /// Has the broker keep the message, for devices that read their command topic when they
+    /// come back after being offline.
+    pub fn retain(mut self) -> Self {
+        self.delivery.retain = true;
+        self
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    pub fn delivery(&self) -> Delivery {
+        self.delivery
+    }
+
+    pub fn confirmation(&self) -> Option<&Confirmation> {
+        self.confirmation.as_ref()
+    }
+
+    pub fn conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+
+    fn describe(&self) -> String {
+        format!("publish {} {}", self.topic, self.payload)
+    }
+}
+
+#[derive(Clone)]
+pub struct Thermostat {
+    entity: String,
+    temperature: f32,
+    instance: Option<String>,
+    confirmation: Option<Confirmation>,
+    conditions: Vec<Condition>,
+}
+
+impl Thermostat {
+    pub fn new(entity: &str, temperature: f32) -> Self {
+        Self {
+            entity: entity.to_string(),
+            temperature,
+            instance: None,
+            confirmation: None,
+            conditions: vec![],
+        }
+    }
+
+    /// Only sets the thermostat if the condition holds when it is due.
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// Checks that the thermostat followed the service call and calls it again if it did not.
+    pub fn confirm(mut self, confirmation: Confirmation) -> Self {
+        self.confirmation = Some(confirmation);
+        self
+    }
+
+    /// Sets the thermostat through the named Home Assistant instance instead of the default
+    /// one.
+    pub fn on(mut self, instance: &str) -> Self {
+        self.instance = Some(instance.to_string());
+        self
+    }
+
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_ref().map(String::as_str)
+    }
+
+    pub fn confirmation(&self) -> Option<&Confirmation> {
+        self.confirmation.as_ref()
+    }
+
+    pub fn conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+
+    fn service_call(&self) -> hass::ServiceCall {
+        hass::set_temperature_call(&self.entity, self.temperature)
+    }
+
+    fn describe(&self) -> String {
+        format!("set {} to {}", self.entity, self.temperature)
+    }
+}
+
+fn target(action: &Action) -> &str {
+    match action {
+        Action::Publish(message) => &message.topic,
+        Action::SetTemperature(thermostat) => &thermostat.entity,
+    }
+}
+
+// Why actions run and when their plan is done, for the payload templates.
+#[derive(Clone)]
+struct Run {
+    reason: &'static str,
+    until: Instant,
+}
+
+fn describe(action: &Action) -> String {
+    match action {
+        Action::Publish(message) => message.describe(),
+        Action::SetTemperature(thermostat) => thermostat.describe(),
+    }
+}
+
+pub struct AutoShutdown<H> {
+    zone: String,
+    hass: hass::Instances<H>,
+    clock: Arc<dyn Clock>,
+    machine: Arc<Mutex<StateMachine>>,
+    // Dropping the sender cancels the pending timer of the state machine.
+    timer: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    // Sending the reason cancels the stages of the running shutdown that have not started.
+    shutdown: Arc<Mutex<Option<oneshot::Sender<String>>>>,
+    doors: Vec<Door>,
+    combine: Combine,
+    status_topic: Option<String>,
+    subscription_qos: QoS,
+    topics: TopicStates,
+    delay: std::time::Duration,
+    sender: futures::sync::mpsc::Sender<OpCode>,
+    plan: Plan,
+    opening: Plan,
+    opening_hours: Option<TimeWindow>,
+    notifications: Vec<Notification>,
+    warning: std::time::Duration,
+    vetoes: Vec<Condition>,
+    veto_recheck: std::time::Duration,
+    captures: Vec<Capture>,
+    snapshot: Arc<Mutex<Option<Snapshot>>>,
+    enforce: bool,
+    exemptions: Vec<String>,
+    // The devices that are being switched off again right now.
+    enforcing: Arc<Mutex<HashSet<String>>>,
+    state_file: Option<StateFile>,
+    debounce: Debounce,
+    door_states: Arc<Mutex<Doors>>,
+}
+
+// Every timer and Home Assistant call feeds its result back into the state machine, so they all
+// need their own handle on it.
+impl<H> Clone for AutoShutdown<H> {
+    fn clone(&self) -> Self {
+        AutoShutdown {
+            zone: self.zone.clone(),
+            hass: self.hass.clone(),
+            clock: Arc::clone(&self.clock),
+            machine: Arc::clone(&self.machine),
+            timer: Arc::clone(&self.timer),
+            shutdown: Arc::clone(&self.shutdown),
+            doors: self.doors.clone(),
+            combine: self.combine,
+            status_topic: self.status_topic.clone(),
+            subscription_qos: self.subscription_qos,
+            topics: self.topics.clone(),
+            delay: self.delay,
+            sender: self.sender.clone(),
+            plan: self.plan.clone(),
+            opening: self.opening.clone(),
+            opening_hours: self.opening_hours,
+            notifications: self.notifications.clone(),
+            warning: self.warning,
+            vetoes: self.vetoes.clone(),
+            veto_recheck: self.veto_recheck,
+            captures: self.captures.clone(),
+            snapshot: Arc::clone(&self.snapshot),
+            enforce: self.enforce,
+            exemptions: self.exemptions.clone(),
+            enforcing: Arc::clone(&self.enforcing),
+            state_file: self.state_file.clone(),
+            debounce: self.debounce,
+            door_states: Arc::clone(&self.door_states),
+        }
+    }
+}
+
+impl<H: Hass + Send + Sync + 'static> AutoShutdown<H> {
+    pub fn new(
+        hass: hass::Instances<H>,
+        topic: &str,
+        delay: std::time::Duration,
+        sender: futures::sync::mpsc::Sender<OpCode>,
+        plan: Plan,
+    ) -> Self {
+        let warning = std::time::Duration::from_secs(0);
+        let veto_recheck = std::time::Duration::from_secs(5 * 60);
+        AutoShutdown {
+            zone: "space".to_string(),
+            hass,
+            clock: Arc::new(SystemClock),
+            machine: Arc::new(Mutex::new(StateMachine::new(delay, warning, veto_recheck))),
+            timer: Arc::new(Mutex::new(None)),
+            shutdown: Arc::new(Mutex::new(None)),
+            doors: vec![Door::new("door", topic)],
+            combine: Combine::All,
+            status_topic: None,
+            subscription_qos: QoS::AtLeastOnce,
+            topics: TopicStates::new(),
+            delay,
+            sender,
+            plan,
+            opening: Plan::new(),
+            opening_hours: None,
+            notifications: vec![],
+            warning,
+            vetoes: vec![],
+            veto_recheck,
+            captures: vec![],
+            snapshot: Arc::new(Mutex::new(None)),
+            enforce: false,
+            exemptions: vec![],
+            enforcing: Arc::new(Mutex::new(HashSet::new())),
+            state_file: None,
+            debounce: Debounce::default(),
+            door_states: Arc::new(Mutex::new(Doors::new(
+                &[Door::new("door", topic)],
+                Combine::All,
+                Debounce::default(),
+            ))),
+        }
+    }
+
+    /// The name payload templates know as `{zone}`, `space` by default. `Zones` names its
+    /// zones.
+    pub fn set_zone(mut self, zone: &str) -> Self {
+        self.zone = zone.to_string();
+        self
+    }
+
+    /// Runs when the door is unlocked after a completed shutdown, after the snapshot has been
+    /// restored. It does not run if the door was unlocked during the countdown or the shutdown.
+    pub fn set_opening(mut self, opening: Plan) -> Self {
+        self.opening = opening;
+        self
+    }
+
+    /// Limits the opening plan to a time of day, e.g. to turn the lights on only when it is
+    /// dark.
+    pub fn set_opening_hours(mut self, hours: TimeWindow) -> Self {
+        self.opening_hours = Some(hours);
+        self
+    }
+
+    /// States recorded just before the shutdown, the ones that are not `without_restore` are
+    /// put back when the door is unlocked.
+    pub fn set_captures(mut self, captures: Vec<Capture>) -> Self {
+        self.captures = captures;
+        self
+    }
+
+    /// The QoS of all subscriptions, at least once by default.
+    pub fn set_subscription_qos(mut self, qos: QoS) -> Self {
+        self.subscription_qos = qos;
+        self
+    }
+
+    pub fn subscription_qos(&self) -> QoS {
+        self.subscription_qos
+    }
+
+    pub fn status_topic(&self) -> Option<&str> {
+        self.status_topic.as_ref().map(String::as_str)
+    }
+
+    pub fn state_file(&self) -> Option<&StateFile> {
+        self.state_file.as_ref()
+    }
+
+    /// All topics the daemon has to subscribe to: the door, the state topics that confirm
+    /// actions, the ones conditions look at and the captured ones.
+    pub fn subscriptions(&self) -> Vec<String> {
+        let actions = self.plan.actions().chain(self.opening.actions());
+        let confirmations =
+            actions.filter_map(
+                |action| match action.confirmation().map(Confirmation::source) {
+                    Some(Source::Mqtt(topic)) => Some(topic.as_str()),
+                    _ => None,
+                },
+            );
+        let conditions = self
+            .plan
+            .actions()
+            .chain(self.opening.actions())
+            .flat_map(Action::conditions)
+            .chain(self.vetoes.iter())
+            .filter_map(Condition::topic);
+        let captures = self.captures.iter().filter_map(|c| match c.source() {
+            snapshot::Source::Mqtt { state, .. } => Some(state.as_str()),
+            _ => None,
+        });
+
+        let mut topics = self
+            .doors
+            .iter()
+            .map(|door| door.topic().to_string())
+            .collect::<Vec<_>>();
+        for topic in confirmations.chain(conditions).chain(captures) {
+            if !topics.iter().any(|t| t == topic) {
+                topics.push(topic.to_string());
+            }
+        }
+        topics
+    }
+
+    /// Keeps the space shut down while the door stays locked: a device whose confirmation topic
+    /// shows something else than the expected state, e.g. the amp after someone turned it on
+    /// remotely, has its action run again.
+    pub fn set_enforcement(mut self, enforce: bool) -> Self {
+        self.enforce = enforce;
+        self
+    }
+
+    /// Devices the enforcement leaves alone, by the topic of their shutdown message or the
+    /// entity of their thermostat.
+    pub fn set_exemptions(mut self, exemptions: Vec<String>) -> Self {
+        self.exemptions = exemptions;
+        self
+    }
+
+    /// Where a running countdown is saved, so `resume` can pick it up after a restart.
+    pub fn set_state_file(mut self, path: &str) -> Self {
+        self.state_file = Some(StateFile::new(path));
+        self
+    }
+
+    /// Like `Door::set_entity`, for the door given to `new`.
+    pub fn set_lock_entity(mut self, entity: &str, locked: &str) -> Self {
+        self.doors[0] = self.doors[0].clone().set_entity(entity, locked);
+        self.reset_doors()
+    }
+
+    /// Replaces the door given to `new` by several ones, e.g. the front and the back door.
+    pub fn set_doors(mut self, doors: Vec<Door>, combine: Combine) -> Self {
+        self.doors = doors;
+        self.combine = combine;
+        self.reset_doors()
+    }
+
+    /// Only passes on lock and unlock readings that stayed stable for a while, and reports a
+    /// flapping sensor.
+    pub fn set_debounce(mut self, debounce: Debounce) -> Self {
+        self.debounce = debounce;
+        self.reset_doors()
+    }
+
+    fn reset_doors(mut self) -> Self {
+        self.door_states = Arc::new(Mutex::new(Doors::new(
+            &self.doors,
+            self.combine,
+            self.debounce,
+        )));
+        self
+    }
+
+    fn reset_machine(mut self) -> Self {
+        self.machine = Arc::new(Mutex::new(StateMachine::new(
+            self.delay,
+            self.warning,
+            self.veto_recheck,
+        )));
+        self
+    }
+
+    /// The clock all timers and time based conditions are driven by.
+    pub fn set_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Conditions that, if any of them holds when the timer expires, stop the shutdown. They are
+    /// evaluated by the default Home Assistant instance.
+    pub fn set_vetoes(mut self, vetoes: Vec<Condition>) -> Self {
+        self.vetoes = vetoes;
+        self
+    }
+
+    /// How long to wait before checking the vetoes again after one of them stopped the shutdown.
+    pub fn set_veto_recheck(mut self, veto_recheck: std::time::Duration) -> Self {
+        self.veto_recheck = veto_recheck;
+        self.reset_machine()
+    }
+
+    /// Topic the state (`idle`, `armed`, `warning`, ...) is published on after every transition.
+    pub fn set_status_topic(mut self, topic: &str) -> Self {
+        self.status_topic = Some(topic.to_string());
+        self
+    }
+
+    pub fn set_notifications(mut self, notifications: Vec<Notification>) -> Self {
+        self.notifications = notifications;
+        self
+    }
+
+    /// How long before the shutdown the `ShutdownImminent` notification is sent.
+    pub fn set_warning(mut self, warning: std::time::Duration) -> Self {
+        self.warning = warning;
+        self.reset_machine()
+    }
+
+    /// Checks all configured Home Assistant actions against the service catalogue of their
+    /// instance so a typo shows up when the daemon starts and not when the timer fires. An
+    /// instance that can not be reached is not checked, the daemon still starts then.
+    pub fn check_services(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if let Err(e) = self.plan.check() {
+            println!("invalid shutdown plan: {}", e);
+            return Box::new(futures::future::err(()));
+        }
+        if let Err(e) = self.opening.check() {
+            println!("invalid opening plan: {}", e);
+            return Box::new(futures::future::err(()));
+        }
+
+        let mut calls: HashMap<Option<&str>, Vec<hass::ServiceUse>> = HashMap::new();
+        let actions = self.plan.actions().chain(self.opening.actions());
+        for thermostat in actions.filter_map(|action| match action {
+            Action::SetTemperature(thermostat) => Some(thermostat),
+            Action::Publish(_) => None,
+        }) {
+            calls
+                .entry(thermostat.instance())
+                .or_default()
+                .push((&thermostat.service_call()).into());
+        }
+        for capture in self.captures.iter() {
+            calls
+                .entry(capture.instance())
+                .or_default()
+                .extend(capture.services());
+        }
+        for notification in self.notifications.iter() {
+            calls
+                .entry(notification.instance())
+                .or_default()
+                .push(notification.service());
+        }
+
+        let checks = calls
+            .into_iter()
+            .map(|(instance, calls)| {
+                let name = instance.unwrap_or("default").to_string();
+                let hass = match self.hass.get(instance) {
+                    Ok(hass) => hass,
+                    Err(e) => {
+                        println!("invalid shutdown actions: {}", e);
+                        return Box::new(futures::future::ok(false))
+                            as Box<dyn Future<Item = bool, Error = ()> + Send>;
+                    }
+                };
+                Box::new(hass.get_services().then(move |r| {
+                    let domains = match r {
+                        Ok(domains) => domains,
+                        Err(e) => {
+                            println!(
+                                "warning: not checking the actions on {}, failed to fetch its \
+                                 service catalogue: {}",
+                                name, e
+                            );
+                            return Ok(true);
+                        }
+                    };
+                    let services = hass::Services::new(domains);
+                    let mut ok = true;
+                    for call in calls.iter() {
+                        if let Err(e) = services.check(call) {
+                            println!("invalid shutdown action {} on {}: {}", call, name, e);
+                            ok = false;
+                        }
+                    }
+                    Ok(ok)
+                }))
+            })
+            .collect::<Vec<_>>();
+
+        let fut = futures::future::join_all(checks).and_then(|checks| {
+            if checks.into_iter().all(|ok| ok) {
+                Ok(())
+            } else {
+                Err(())
+            }
+        });
+
+        Box::new(fut)
+    }
+
+    /// Evaluates the vetoes and returns the first one that holds. A veto that can not be
+    /// evaluated does not hold up the shutdown.
+    fn check_vetoes(&self) -> Box<dyn Future<Item = Option<String>, Error = ()> + Send> {
+        let context = self.context(self.hass.default());
+        let futures = self
+            .vetoes
+            .iter()
+            .cloned()
+            .map(|veto| {
+                veto.evaluate(&context).then(move |r| match r {
+                    Ok(true) => Ok(Some(veto.to_string())),
+                    Ok(false) => Ok(None),
+                    Err(e) => {
+                        println!("failed to evaluate veto {}: {}", veto, e);
+                        Ok(None)
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(futures::future::join_all(futures).map(|r| r.into_iter().flatten().next()))
+    }
+
+    fn notify(&self, event: notify::Event) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(
+            notify::send(&self.hass, &self.notifications, &event).map(move |report| {
+                if !report.is_success() {
+                    println!("notifications for {:?}: {}", event.kind(), report)
+                }
+            }),
+        )
+    }
+
+    // Dropping the sender of `cancel` without sending lets all stages run.
+    fn shutdown_futures(
+        &self,
+        cancel: oneshot::Receiver<String>,
+    ) -> Box<dyn Future<Item = ShutdownReport, Error = ()> + Send> {
+        let this = self.clone();
+        let start = self.clock.now();
+        let run = Run {
+            reason: "door locked",
+            until: start + self.plan.duration(),
+        };
+        let cancel = cancel.or_else(|_| futures::future::empty());
+        self.plan.run(&self.clock, start, cancel, move |action| {
+            this.run_action(action, &run)
+        })
+    }
+
+    fn context<'a>(&'a self, hass: &'a H) -> Context<'a, H> {
+        Context {
+            hass,
+            topics: &self.topics,
+            clock: &self.clock,
+        }
+    }
+
+    /// Evaluates the conditions of an action when it is due and only runs it if all of them
+    /// hold. If a condition can not be evaluated the action is not run either, e.g. the printer
+    /// is left alone when its state is unknown.
+    fn run_action(
+        &self,
+        action: &Action,
+        run: &Run,
+    ) -> Box<dyn Future<Item = Outcome, Error = ()> + Send> {
+        if action.conditions().is_empty() {
+            return self.run_confirmed(action, run);
+        }
+
+        // gated on the instance the action goes to, e.g. the heating in the workshop
+        let hass = match self.hass.get(action.instance()) {
+            Ok(hass) => hass,
+            Err(e) => {
+                return Box::new(futures::future::ok(Outcome::new(
+                    describe(action),
+                    Err(format!("failed to evaluate conditions: {}", e)),
+                )))
+            }
+        };
+        let context = self.context(hass);
+        let checks = action
+            .conditions()
+            .iter()
+            .map(|condition| {
+                let description = condition.to_string();
+                condition
+                    .evaluate(&context)
+                    .map(move |holds| (description, holds))
+            })
+            .collect::<Vec<_>>();
+
+        let this = self.clone();
+        let action = action.clone();
+        let run = run.clone();
+        Box::new(futures::future::join_all(checks).then(move |r| {
+            let description = describe(&action);
+            match r {
+                Ok(checks) => match checks.into_iter().find(|(_, holds)| !holds) {
+                    Some((condition, _)) => {
+                        println!("skipping {}: {} does not hold", description, condition);
+                        futures::future::Either::A(futures::future::ok(Outcome::skipped(
+                            description,
+                            format!("{} does not hold", condition),
+                        )))
+                    }
+                    None => futures::future::Either::B(this.run_confirmed(&action, &run)),
+                },
+                Err(e) => {
+                    println!("not running {}, conditions failed: {}", description, e);
+                    futures::future::Either::A(futures::future::ok(Outcome::new(
+                        description,
+                        Err(format!("failed to evaluate conditions: {}", e)),
+                    )))
+                }
+            }
+        }))
+    }
+
+    /// Runs an action and, if it declares a confirmation, waits for it and issues the action
+    /// again until it is confirmed or out of attempts.
+    fn run_confirmed(
+        &self,
+        action: &Action,
+        run: &Run,
+    ) -> Box<dyn Future<Item = Outcome, Error = ()> + Send> {
+        let confirmation = match action.confirmation() {
+            Some(confirmation) => confirmation.clone(),
+            None => return self.issue(action, run),
+        };
+
+        let this = self.clone();
+        let action = action.clone();
+        let run = run.clone();
+        let device = target(&action).to_string();
+        Box::new(futures::future::loop_fn(1, move |attempt| {
+            let confirmation = confirmation.clone();
+            let device = device.clone();
+            let waiting = {
+                let this = this.clone();
+                let confirmation = confirmation.clone();
+                move || confirmation.wait(&this.hass, &this.topics, &this.clock)
+            };
+            this.issue(&action, &run).and_then(move |outcome| {
+                // the action itself failed, that is not for the device to confirm
+                if outcome.result.is_err() {
+                    return Either::A(futures::future::ok(Loop::Break(outcome)));
+                }
+                Either::B(waiting().map(move |confirmed| match confirmed {
+                    Ok(()) => Loop::Break(outcome),
+                    Err(e) if attempt < confirmation.attempts() => {
+                        println!(
+                            "{} not confirmed by {} ({}), attempt {} of {}",
+                            outcome.action,
+                            confirmation.source(),
+                            e,
+                            attempt,
+                            confirmation.attempts()
+                        );
+                        Loop::Continue(attempt + 1)
+                    }
+                    Err(e) => {
+                        println!("{} refused: {}", device, e);
+                        Loop::Break(Outcome::refused(outcome.action, device, e))
+                    }
+                }))
+            })
+        }))
+    }
+
+    fn issue(
+        &self,
+        action: &Action,
+        run: &Run,
+    ) -> Box<dyn Future<Item = Outcome, Error = ()> + Send> {
+        match action.clone() {
+            Action::SetTemperature(thermostat) => {
+                let call = thermostat.service_call();
+                Box::new(self.hass.call(thermostat.instance(), &call).then(move |r| {
+                    let result = match r {
+                        Ok(r) => {
+                            println!(
+                                "set temperatur in {} to {}: {:?}",
+                                thermostat.entity, thermostat.temperature, r
+                            );
+                            Ok(())
+                        }
+                        Err(e) => {
+                            println!("failed to set temperature: {}", e);
+                            Err(e.to_string())
+                        }
+                    };
+                    Ok(Outcome::new(thermostat.describe(), result))
+                }))
+            }
+            Action::Publish(msg) => {
+                let action = msg.describe();
+                let value = msg.payload.render(&payload::Context {
+                    reason: run.reason,
+                    timestamp: self.clock.utc_now(),
+                    zone: &self.zone,
+                    plan_remaining: run.until.saturating_duration_since(self.clock.now()),
+                });
+                Box::new(
+                    self.sender
+                        .clone()
+                        .send(OpCode::Publish((
+                            msg.topic.clone(),
+                            value.clone(),
+                            self.delivery(&msg),
+                        )))
+                        .then(move |r| {
+                            let result = match r {
+                                Ok(_) => {
+                                    println!("published {} {}", msg.topic, value);
+                                    Ok(())
+                                }
+                                Err(e) => Err(e.to_string()),
+                            };
+                            Ok(Outcome::new(action, result))
+                        }),
+                )
+            }
+        }
+    }
+
+    // The command topics the shutdown publishes to with `retain`. Anything else sent to them, by
+    // the opening plan or a restore, has to be retained as well, or a device that reconnects
+    // reads the retained shutdown message and turns off again.
+    fn retained_topics(&self) -> HashSet<String> {
+        self.plan
+            .actions()
+            .filter_map(|action| match action {
+                Action::Publish(msg) if msg.delivery.retain => Some(msg.topic.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn delivery(&self, msg: &ShutdownMessage) -> Delivery {
+        let mut delivery = msg.delivery;
+        delivery.retain |= self.retained_topics().contains(&msg.topic);
+        delivery
+    }
+
+    pub fn state(&self) -> State {
+        self.machine.lock().expect("Mutex poisoned").state()
+    }
+
+    /// Feeds an event into the state machine and carries out the resulting effects.
+    fn dispatch(&self, event: Event) {
+        let now = self.clock.now();
+        let (before, after, mut effects) = {
+            let mut machine = self.machine.lock().expect("Mutex poisoned");
+            let before = machine.state();
+            let effects = machine.handle(event, now);
+            (before, machine.state(), effects)
+        };
+
+        // A snapshot resumed from before a restart belongs to the shutdown that was interrupted.
+        // Unlocked before that ran again, the devices it already switched off are put back, so
+        // the snapshot does not linger until the next shutdown.
+        let restores = effects.iter().any(|e| match e {
+            Effect::Restore { .. } => true,
+            _ => false,
+        });
+        if after == State::Idle
+            && !restores
+            && self.snapshot.lock().expect("Mutex poisoned").is_some()
+        {
+            effects.push(Effect::Restore { open: false });
+        }
+
+        if before != after {
+            self.save(after, now);
+            self.publish_status(after, now);
+        }
+        for effect in effects {
+            self.apply(effect);
+        }
+    }
+
+    fn apply(&self, effect: Effect) {
+        match effect {
+            Effect::StartTimer(at) => {
+                let (sender, receiver) = oneshot::channel();
+                // replacing the sender cancels the previous timer
+                *self.timer.lock().expect("Mutex poisoned") = Some(sender);
+                let this = self.clone();
+                let fut = self
+                    .clock
+                    .delay_until(at)
+                    .select2(receiver.map_err(|_| ()))
+                    .then(move |r| {
+                        if let Ok(Either::A(_)) = r {
+                            this.dispatch(Event::Timer);
+                        }
+                        Ok(())
+                    });
+                tokio::spawn(fut);
+            }
+            Effect::CancelTimer => {
+                println!("Stopping timer");
+                self.timer.lock().expect("Mutex poisoned").take();
+            }
+            Effect::Notify(event) => {
+                tokio::spawn(self.notify(event));
+            }
+            Effect::CheckVetoes => {
+                let this = self.clone();
+                let fut = self
+                    .check_vetoes()
+                    .map(move |vetoed_by| this.dispatch(Event::VetoesChecked { vetoed_by }));
+                tokio::spawn(fut);
+            }
+            Effect::RunShutdown => {
+                let (cancel, cancelled) = oneshot::channel();
+                *self.shutdown.lock().expect("Mutex poisoned") = Some(cancel);
+                let this = self.clone();
+                let resumed = self.snapshot.lock().expect("Mutex poisoned").clone();
+                let take: Box<dyn Future<Item = Snapshot, Error = ()> + Send> = match resumed {
+                    // the shutdown already ran partly, the devices may be off by now
+                    Some(snapshot) => {
+                        println!("keeping the snapshot taken before the restart");
+                        Box::new(futures::future::ok(snapshot))
+                    }
+                    None => Snapshot::take(&self.captures, &self.hass, &self.topics),
+                };
+                let fut = take
+                    .and_then(move |taken| {
+                        *this.snapshot.lock().expect("Mutex poisoned") = Some(taken);
+                        this.save(this.state(), this.clock.now());
+                        let shutdown = this.shutdown_futures(cancelled);
+                        shutdown.map(move |report| (this, report))
+                    })
+                    .map(move |(this, report)| {
+                        println!("shutdown finished: {}", report);
+                        let failure = if report.is_success() {
+                            None
+                        } else {
+                            Some(report.to_string())
+                        };
+                        this.dispatch(Event::ShutdownFinished { failure });
+                    });
+                tokio::spawn(fut);
+            }
+            Effect::CancelShutdown => {
+                if let Some(cancel) = self.shutdown.lock().expect("Mutex poisoned").take() {
+                    println!("skipping the stages that have not started yet");
+                    let _ = cancel.send("door unlocked".to_string());
+                }
+            }
+            Effect::Restore { open } => {
+                let snapshot = self.snapshot.lock().expect("Mutex poisoned").take();
+                if snapshot.is_some() {
+                    self.save(self.state(), self.clock.now());
+                } else if !self.captures.is_empty() {
+                    println!("nothing to restore, no snapshot was taken");
+                }
+                let restore: Box<dyn Future<Item = (), Error = ()> + Send> = match snapshot {
+                    Some(snapshot) => Box::new(
+                        snapshot
+                            .restore(&self.hass, &self.sender, &self.retained_topics())
+                            .map(|report| println!("restored the snapshot: {}", report)),
+                    ),
+                    None => Box::new(futures::future::ok(())),
+                };
+                let this = self.clone();
+                tokio::spawn(restore.and_then(move |_| {
+                    if open {
+                        this.open()
+                    } else {
+                        Box::new(futures::future::ok(()))
+                    }
+                }));
+            }
+        }
+    }
+
+    /// Runs the actions confirmed by `topic` again if it no longer shows their expected state
+    /// while the space is shut down.
+    fn enforce(&self, topic: &str, value: &str) {
+        if !self.enforce || self.state() != State::Off {
+            return;
+        }
+        for action in self.plan.actions() {
+            let confirmation = match action.confirmation() {
+                Some(confirmation) => confirmation,
+                None => continue,
+            };
+            match confirmation.source() {
+                Source::Mqtt(t) if t == topic => {}
+                _ => continue,
+            }
+            match confirmation.value(value) {
+                Some(state) if state != confirmation.expected() => {}
+                _ => continue,
+            }
+            let device = target(action).to_string();
+            if self.exemptions.contains(&device) {
+                println!("{} reports {}, exempt from enforcement", device, value);
+                continue;
+            }
+            if !self
+                .enforcing
+                .lock()
+                .expect("Mutex poisoned")
+                .insert(device.clone())
+            {
+                continue;
+            }
+
+            println!("enforcing the shutdown: {} reports {}", device, value);
+            let enforcing = Arc::clone(&self.enforcing);
+            let run = Run {
+                reason: "switched on while locked",
+                until: self.clock.now(),
+            };
+            tokio::spawn(self.run_action(action, &run).map(move |outcome| {
+                enforcing.lock().expect("Mutex poisoned").remove(&device);
+                match outcome.result {
+                    Ok(()) => println!("enforced {}", outcome.action),
+                    Err(e) => println!("failed to enforce {}: {}", outcome.action, e),
+                }
+            }));
+        }
+    }
+
+    fn open(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let local = self.clock.utc_now().with_timezone(&chrono::Local).time();
+        if let Some(hours) = self.opening_hours {
+            if !hours.contains(local) {
+                println!("not opening, {} is outside of {}", local, hours);
+                return Box::new(futures::future::ok(()));
+            }
+        }
+
+        let this = self.clone();
+        let start = self.clock.now();
+        let run = Run {
+            reason: "door unlocked",
+            until: start + self.opening.duration(),
+        };
+        Box::new(
+            self.opening
+                .run(
+                    &self.clock,
+                    start,
+                    futures::future::empty(),
+                    move |action| this.run_action(action, &run),
+                )
+                .map(|report| println!("opening finished: {}", report)),
+        )
+    }
+
+    /// Continues the countdown saved by the previous run of the daemon. An overdue shutdown
+    /// runs right away, after the vetoes have been checked.
+    pub fn resume(&self) {
+        let saved = match self.state_file.as_ref().and_then(StateFile::load) {
+            Some(saved) => saved,
+            None => return,
+        };
+        match saved {
+            Saved::Off { snapshot } => {
+                println!("resuming after the shutdown");
+                *self.snapshot.lock().expect("Mutex poisoned") = snapshot;
+                self.dispatch(Event::ResumedOff);
+            }
+            Saved::Armed { deadline, snapshot } => {
+                *self.snapshot.lock().expect("Mutex poisoned") = snapshot;
+                let remaining = (deadline - self.clock.utc_now())
+                    .to_std()
+                    .unwrap_or_else(|_| std::time::Duration::from_secs(0));
+                println!("resuming the countdown, {}s left", remaining.as_secs());
+                self.dispatch(Event::Resumed {
+                    deadline: self.clock.now() + remaining,
+                });
+            }
+        }
+    }
+
+    fn save(&self, state: State, now: Instant) {
+        let state_file = match &self.state_file {
+            Some(state_file) => state_file,
+            None => return,
+        };
+        // Taken when the shutdown starts and gone once it has been restored.
+        let snapshot = self.snapshot.lock().expect("Mutex poisoned").clone();
+        let saved = match state {
+            State::Armed { deadline } | State::Warning { deadline } => {
+                // a delay of centuries is as good as forever, and still fits into a date
+                let remaining = deadline.saturating_duration_since(now).min(MAX_SAVED_DELAY);
+                Some(Saved::Armed {
+                    deadline: self.clock.utc_now()
+                        + chrono::Duration::from_std(remaining)
+                            .unwrap_or_else(|_| chrono::Duration::zero()),
+                    snapshot,
+                })
+            }
+            // Interrupted by a restart these run again as soon as the daemon is back.
+            State::Vetoed { .. } | State::ShuttingDown { locked: true, .. } => Some(Saved::Armed {
+                deadline: self.clock.utc_now(),
+                snapshot,
+            }),
+            State::Off => Some(Saved::Off { snapshot }),
+            State::Idle | State::ShuttingDown { locked: false, .. } => None,
+        };
+        state_file.save(saved.as_ref());
+    }
+
+    /// Brings the state machine in line with the lock states known to Home Assistant, for the
+    /// doors that have an entity. Entities that are `unavailable` or `unknown` are left alone.
+    pub fn reconcile(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let reads = self
+            .doors
+            .iter()
+            .filter_map(|door| {
+                let (entity, locked) = door.entity()?;
+                let (entity, locked) = (entity.to_string(), locked.to_string());
+                let name = door.name().to_string();
+                let this = self.clone();
+                Some(self.hass.default().get_state(&entity).then(move |r| {
+                    match r {
+                        Ok(state) if state.name == "unavailable" || state.name == "unknown" => {
+                            println!("lock state of {} is {}", entity, state.name)
+                        }
+                        Ok(state) => {
+                            println!("{} is {}", entity, state.name);
+                            let outputs = this.door_states.lock().expect("Mutex poisoned").set(
+                                &name,
+                                state.name == locked,
+                                this.clock.now(),
+                            );
+                            this.doors_changed(outputs);
+                        }
+                        Err(e) => println!("failed to read the lock state from {}: {}", entity, e),
+                    }
+                    Ok(())
+                }))
+            })
+            .collect::<Vec<_>>();
+        Box::new(futures::future::join_all(reads).map(|_| ()))
+    }
+
+    fn door(&self, topic: &str, payload: &str) {
+        let outputs = self.door_states.lock().expect("Mutex poisoned").update(
+            topic,
+            payload,
+            self.clock.now(),
+        );
+        self.doors_changed(outputs);
+    }
+
+    fn doors_changed(&self, outputs: Vec<door::Output>) {
+        let before = self.state();
+        let mut door_changed = false;
+        for output in outputs {
+            match output {
+                door::Output::Locked(true) => self.dispatch(Event::Locked),
+                door::Output::Locked(false) => self.dispatch(Event::Unlocked),
+                door::Output::DoorChanged => door_changed = true,
+                door::Output::SettleAt(at) => {
+                    let this = self.clone();
+                    tokio::spawn(self.clock.delay_until(at).map(move |_| {
+                        let outputs = this
+                            .door_states
+                            .lock()
+                            .expect("Mutex poisoned")
+                            .settle(this.clock.now());
+                        this.doors_changed(outputs);
+                    }));
+                }
+                door::Output::Flapping { door, changes } => {
+                    println!("{} changed {} times, is the sensor broken?", door, changes);
+                    tokio::spawn(self.notify(notify::Event::SensorFault {
+                        sensor: door,
+                        changes,
+                    }));
+                }
+            }
+        }
+        // Transitions are published anyway, a door that did not cause one only shows in the
+        // status.
+        let state = self.state();
+        if door_changed && self.doors.len() > 1 && state == before {
+            self.publish_status(state, self.clock.now());
+        }
+    }
+
+    fn publish_status(&self, state: State, now: Instant) {
+        let topic = match &self.status_topic {
+            Some(topic) => topic.clone(),
+            None => return,
+        };
+        let mut status = serde_json::json!({ "state": state.name() });
+        match state {
+            State::Armed { deadline } | State::Warning { deadline } => {
+                status["remaining"] = deadline.saturating_duration_since(now).as_secs().into();
+            }
+            _ => {}
+        }
+        if self.doors.len() > 1 {
+            let doors = self.door_states.lock().expect("Mutex poisoned");
+            for (door, locked) in doors.states() {
+                status["doors"][door.name()] = match locked {
+                    Some(true) => "locked",
+                    Some(false) => "unlocked",
+                    None => "unknown",
+                }
+                .into();
+            }
+        }
+        let fut = self
+            .sender
+            .clone()
+            .send(OpCode::Publish((
+                topic,
+                status.to_string(),
+                Delivery::default(),
+            )))
+            .map(|_| ())
+            .map_err(|e| println!("failed to publish the status: {}", e));
+        tokio::spawn(fut);
+    }
+
+    pub fn handle_msg(&self, msg: mqtt::OpCode) {
+        match msg {
+            OpCode::MessageReceived((topic, value)) => {
+                println!("<msg: {} {}", topic, value);
+                self.topics.update(&topic, &value);
+                self.enforce(&topic, &value);
+                self.door(&topic, &value);
+            }
+            OpCode::RetainedReceived((topic, value)) => {
+                println!("<retained: {} {}", topic, value);
+                self.topics.update(&topic, &value);
+                self.enforce(&topic, &value);
+                match self.doors.iter().find(|door| door.topic() == topic) {
+                    Some(door) if door.entity().is_some() => println!(
+                        "ignoring the retained lock state of {}, asking Home Assistant",
+                        door.name()
+                    ),
+                    _ => self.door(&topic, &value),
+                }
+            }
+            // `Zones` subscribes again, with the QoS merged across the zones.
+            OpCode::Reconnected => {
+                tokio::spawn(self.reconcile());
+            }
+            e => println!("unhandled message: {:?}", e),
+        };
+    }
+}
+
+// Everything at once, for tests that do not care about stages.
+#[cfg(test)]
+pub fn default_plan(shutdown_messages: Vec<ShutdownMessage>, thermostats: Vec<Thermostat>) -> Plan {
+    use crate::plan::Stage;
+
+    let mut stage = Stage::new("shutdown", std::time::Duration::from_secs(0));
+    for message in shutdown_messages {
+        stage = stage.add_message(message);
+    }
+    for thermostat in thermostats {
+        stage = stage.add_thermostat(thermostat);
+    }
+    Plan::new().add_stage(stage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::plan::Stage;
+    use chrono::TimeZone;
+    use futures::future::lazy;
+    use futures::stream::Stream;
+    use futures::IntoFuture;
+    use std::time::{Duration, Instant};
+
+    fn run_one<F>(f: F) -> std::result::Result<F::Item, F::Error>
+    where
+        F: IntoFuture,
+        F::Future: Send + 'static,
+        F::Item: Send + 'static,
+        F::Error: Send + 'static,
+    {
+        let mut runtime = tokio::runtime::Runtime::new().expect("Unable to create a runtime");
+        runtime.block_on(f.into_future())
+    }
+
+    // Runs the spawned tasks on the test thread, in between the steps of a test.
+    fn runtime() -> tokio::runtime::current_thread::Runtime {
+        tokio::runtime::current_thread::Runtime::new().expect("Unable to create a runtime")
+    }
+
+    // Gives every spawned task the chance to run until it waits for something. No real time has
+    // to pass for that, the timers are left to a `ManualClock`.
+    fn settle() -> impl Future<Item = (), Error = ()> {
+        let mut rounds = 0;
+        futures::future::poll_fn(move || {
+            if rounds == 100 {
+                return Ok(futures::Async::Ready(()));
+            }
+            rounds += 1;
+            futures::task::current().notify();
+            Ok(futures::Async::NotReady)
+        })
+    }
+
+    fn auto_shutdown(
+        hass: hass::FakeHass,
+    ) -> (
+        AutoShutdown<hass::FakeHass>,
+        futures::sync::mpsc::Receiver<OpCode>,
+    ) {
+        auto_shutdown_with(
+            hass,
+            default_plan(
+                vec![ShutdownMessage::new("lounge/amp/set", "0")],
+                vec![Thermostat::new("climate.lounge", 18.0)],
+            ),
+        )
+    }
+
+    fn auto_shutdown_with(
+        hass: hass::FakeHass,
+        plan: Plan,
+    ) -> (
+        AutoShutdown<hass::FakeHass>,
+        futures::sync::mpsc::Receiver<OpCode>,
+    ) {
+        let (tx, rx) = futures::sync::mpsc::channel(16);
+        let auto_shutdown = AutoShutdown::new(
+            hass::Instances::new("default", hass),
+            "door/lock",
+            Duration::from_millis(10),
+            tx,
+            plan,
+        );
+        (auto_shutdown, rx)
+    }
+
+    // The temperatures `climate.set_temperature` was called with, in order.
+    fn temperatures(hass: &hass::FakeHass) -> Vec<f64> {
+        hass.calls()
+            .into_iter()
+            .filter(|c| c.to_string() == "climate.set_temperature")
+            .map(|c| {
+                c.data
+                    .unwrap()
+                    .get("temperature")
+                    .unwrap()
+                    .as_f64()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn door(value: &str) -> OpCode {
+        OpCode::MessageReceived(("door/lock".to_string(), value.to_string()))
+    }
+
+    #[test]
+    fn shutdown_sets_thermostats_and_publishes() {
+        let hass = hass::FakeHass::new();
+        let (auto_shutdown, rx) = auto_shutdown(hass.clone());
+
+        let report = run_one(auto_shutdown.shutdown_futures(oneshot::channel().1)).unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.outcomes().count(), 2);
+
+        let calls = hass.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].to_string(), "climate.set_temperature");
+
+        let published = rx.take(1).collect().wait().unwrap();
+        match &published[..] {
+            [OpCode::Publish((topic, value, _))] => {
+                assert_eq!(topic, "lounge/amp/set");
+                assert_eq!(value, "0");
+            }
+            o => panic!("unexpected messages: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn messages_are_published_with_their_qos_and_retain_flag() {
+        let (auto_shutdown, rx) = auto_shutdown_with(
+            hass::FakeHass::new(),
+            default_plan(
+                vec![ShutdownMessage::new("lounge/relay/set", "OFF")
+                    .qos(QoS::ExactlyOnce)
+                    .retain()],
+                vec![],
+            ),
+        );
+
+        run_one(auto_shutdown.shutdown_futures(oneshot::channel().1)).unwrap();
+        let published = rx.take(1).collect().wait().unwrap();
+        match &published[..] {
+            [OpCode::Publish((_, _, delivery))] => assert_eq!(
+                *delivery,
+                Delivery {
+                    qos: QoS::ExactlyOnce,
+                    retain: true
+                }
+            ),
+            o => panic!("unexpected messages: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn unconfirmed_actions_are_retried_and_reported() {
+        let hass = hass::FakeHass::new().with_state("climate.lounge", "heat", None);
+        let timeout = Duration::from_millis(20);
+        let (auto_shutdown, rx) = auto_shutdown_with(
+            hass.clone(),
+            default_plan(
+                vec![ShutdownMessage::new("lounge/amp/set", "0").confirm(
+                    Confirmation::mqtt("lounge/amp/state", "0")
+                        .set_timeout(timeout)
+                        .set_attempts(2),
+                )],
+                vec![Thermostat::new("climate.lounge", 18.0)
+                    .confirm(Confirmation::hass("climate.lounge", "heat").set_timeout(timeout))],
+            ),
+        );
+        assert_eq!(
+            auto_shutdown.subscriptions(),
+            vec!["door/lock", "lounge/amp/state"]
+        );
+
+        let report = run_one(auto_shutdown.shutdown_futures(oneshot::channel().1)).unwrap();
+
+        assert_eq!(report.refused().collect::<Vec<_>>(), vec!["lounge/amp/set"]);
+        assert_eq!(hass.calls().len(), 1);
+        let published = rx.take(2).collect().wait().unwrap();
+        assert_eq!(published.len(), 2);
+    }
+
+    #[test]
+    fn failed_actions_are_not_blamed_on_the_device() {
+        let hass = hass::FakeHass::new();
+        let (auto_shutdown, _rx) = auto_shutdown_with(
+            hass.clone(),
+            default_plan(
+                vec![],
+                vec![Thermostat::new("climate.attic", 15.0)
+                    .on("attic")
+                    .confirm(Confirmation::hass("climate.attic", "heat").set_attempts(3))],
+            ),
+        );
+
+        let report = run_one(auto_shutdown.shutdown_futures(oneshot::channel().1)).unwrap();
+
+        assert_eq!(report.failures().count(), 1);
+        assert_eq!(report.refused().count(), 0);
+    }
+
+    #[test]
+    fn conditions_are_evaluated_on_the_instance_of_the_action() {
+        let workshop = hass::FakeHass::new().with_state("climate.workshop", "heat", None);
+        let (tx, _rx) = futures::sync::mpsc::channel(16);
+        let auto_shutdown = AutoShutdown::new(
+            hass::Instances::new("default", hass::FakeHass::new())
+                .add("workshop", workshop.clone()),
+            "door/lock",
+            Duration::from_millis(10),
+            tx,
+            default_plan(
+                vec![],
+                vec![Thermostat::new("climate.workshop", 15.0)
+                    .on("workshop")
+                    .when(Condition::State {
+                        entity: "climate.workshop".to_string(),
+                        state: "heat".to_string(),
+                    })],
+            ),
+        );
+
+        let report = run_one(auto_shutdown.shutdown_futures(oneshot::channel().1)).unwrap();
+        assert!(report.is_success());
+        assert_eq!(workshop.calls().len(), 1);
+    }
+
+    #[test]
+    fn conditions_are_evaluated_when_the_action_is_due() {
+        let hass = hass::FakeHass::new().with_state("sensor.printer_state", "printing", None);
+        let not_printing = Condition::not(Condition::State {
+            entity: "sensor.printer_state".to_string(),
+            state: "printing".to_string(),
+        });
+        let (auto_shutdown, rx) = auto_shutdown_with(
+            hass,
+            default_plan(
+                vec![
+                    ShutdownMessage::new("lounge/amp/set", "0").when(Condition::Mqtt {
+                        topic: "lounge/amp/state".to_string(),
+                        value: "1".to_string(),
+                    }),
+                    ShutdownMessage::new("lounge/printer/set", "0").when(not_printing),
+                ],
+                vec![],
+            ),
+        );
+        assert_eq!(
+            auto_shutdown.subscriptions(),
+            vec!["door/lock", "lounge/amp/state"]
+        );
+        auto_shutdown.topics.update("lounge/amp/state", "1");
+
+        let report = run_one(auto_shutdown.shutdown_futures(oneshot::channel().1)).unwrap();
+        assert!(report.is_success());
+        assert_eq!(
+            report
+                .skipped()
+                .map(|o| o.action.as_str())
+                .collect::<Vec<_>>(),
+            vec!["publish lounge/printer/set 0"]
+        );
+
+        drop(auto_shutdown);
+        let published = rx.collect().wait().unwrap();
+        match &published[..] {
+            [OpCode::Publish((topic, _, _))] => assert_eq!(topic, "lounge/amp/set"),
+            o => panic!("unexpected messages: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn check_services_rejects_unknown_service() {
+        let (auto_shutdown, _rx) = auto_shutdown(hass::FakeHass::new());
+        assert!(run_one(auto_shutdown.check_services()).is_err());
+    }
+
+    #[test]
+    fn check_services_covers_every_restore_of_a_capture() {
+        let check = |services: serde_json::Value| {
+            let domains = serde_json::from_value(serde_json::json!([
+                {
+                    "domain": "climate",
+                    "services": {"set_temperature": {"fields": {"temperature": {}}}},
+                },
+                {"domain": "homeassistant", "services": services},
+            ]))
+            .unwrap();
+            let (auto_shutdown, _rx) = auto_shutdown(hass::FakeHass::new().with_services(domains));
+            let auto_shutdown = auto_shutdown.set_captures(vec![Capture::switch("light.leds")]);
+            run_one(auto_shutdown.check_services())
+        };
+        // the lights may have been off
+        assert!(check(serde_json::json!({"turn_on": {}})).is_err());
+        assert!(check(serde_json::json!({"turn_on": {}, "turn_off": {}})).is_ok());
+    }
+
+    #[test]
+    fn check_services_skips_unreachable_instances() {
+        let (auto_shutdown, _rx) = auto_shutdown(hass::FakeHass::new().unreachable());
+        assert!(run_one(auto_shutdown.check_services()).is_ok());
+    }
+
+    #[test]
+    fn thermostats_are_set_on_their_instance() {
+        let ground_floor = hass::FakeHass::new();
+        let first_floor = hass::FakeHass::new();
+        let (tx, _rx) = futures::sync::mpsc::channel(16);
+        let auto_shutdown = AutoShutdown::new(
+            hass::Instances::new("ground_floor", ground_floor.clone())
+                .add("first_floor", first_floor.clone()),
+            "door/lock",
+            Duration::from_millis(10),
+            tx,
+            default_plan(
+                vec![],
+                vec![
+                    Thermostat::new("climate.lounge", 18.0),
+                    Thermostat::new("climate.workshop", 15.0).on("first_floor"),
+                    Thermostat::new("climate.attic", 15.0).on("attic"),
+                ],
+            ),
+        );
+
+        let report = run_one(auto_shutdown.shutdown_futures(oneshot::channel().1)).unwrap();
+
+        assert_eq!(report.failures().count(), 1);
+        assert_eq!(ground_floor.calls().len(), 1);
+        assert_eq!(first_floor.calls().len(), 1);
+    }
+
+    #[test]
+    fn locking_the_door_runs_the_shutdown() {
+        let hass = hass::FakeHass::new();
+        let (auto_shutdown, _rx) = auto_shutdown(hass.clone());
+
+        run_one(lazy(move || {
+            auto_shutdown.handle_msg(door("1"));
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+        }))
+        .unwrap();
+
+        assert_eq!(hass.calls().len(), 1);
+    }
+
+    #[test]
+    fn notifications_follow_the_countdown() {
+        let hass = hass::FakeHass::new();
+        let (auto_shutdown, _rx) = auto_shutdown(hass.clone());
+        let auto_shutdown = auto_shutdown
+            .set_notifications(vec![Notification::new(
+                notify::Notifier::Notify("space_bot".to_string()),
+                &[
+                    notify::EventKind::CountdownStarted,
+                    notify::EventKind::ShutdownImminent,
+                    notify::EventKind::ShutdownCompleted,
+                ],
+            )])
+            .set_warning(Duration::from_millis(5));
+
+        run_one(lazy(move || {
+            auto_shutdown.handle_msg(door("1"));
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+        }))
+        .unwrap();
+
+        let messages = hass
+            .calls()
+            .into_iter()
+            .filter(|c| c.domain == "notify")
+            .map(|c| c.data.unwrap().get("message").unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[2], "The space has been powered down.");
+    }
+
+    #[test]
+    fn veto_stops_the_shutdown() {
+        let hass = hass::FakeHass::new().with_template("{{ someone_is_here }}", "true");
+        let (auto_shutdown, _rx) = auto_shutdown(hass.clone());
+        let auto_shutdown = auto_shutdown.set_vetoes(vec![
+            Condition::Template("{{ nobody_is_here }}".to_string()),
+            Condition::Template("{{ someone_is_here }}".to_string()),
+        ]);
+
+        run_one(lazy(move || {
+            auto_shutdown.handle_msg(door("1"));
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+        }))
+        .unwrap();
+
+        assert!(hass.calls().is_empty());
+    }
+
+    #[test]
+    fn transitions_are_published() {
+        let (auto_shutdown, rx) = auto_shutdown(hass::FakeHass::new());
+        let auto_shutdown = auto_shutdown.set_status_topic("shutdown/status");
+        let handle = auto_shutdown.clone();
+
+        run_one(lazy(move || {
+            auto_shutdown.handle_msg(door("1"));
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+        }))
+        .unwrap();
+
+        assert_eq!(handle.state(), State::Off);
+        let states = rx
+            .take(3)
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .filter_map(|op| match op {
+                OpCode::Publish((topic, status, _)) if topic == "shutdown/status" => Some(status),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(states[0], r#"{"remaining":0,"state":"armed"}"#);
+        assert_eq!(states[1], r#"{"state":"shutting_down"}"#);
+    }
+
+    #[test]
+    fn space_is_closed_when_both_doors_are_locked() {
+        let (auto_shutdown, rx) = auto_shutdown(hass::FakeHass::new());
+        let auto_shutdown = auto_shutdown.set_status_topic("shutdown/status").set_doors(
+            vec![
+                Door::new("front", "door/front"),
+                Door::new("back", "door/back"),
+            ],
+            Combine::All,
+        );
+        assert_eq!(
+            auto_shutdown.subscriptions(),
+            vec!["door/front", "door/back"]
+        );
+        let lock = |topic: &str| OpCode::MessageReceived((topic.to_string(), "1".to_string()));
+
+        let states = run_one(lazy(move || {
+            auto_shutdown.handle_msg(lock("door/front"));
+            assert_eq!(auto_shutdown.state(), State::Idle);
+            auto_shutdown.handle_msg(lock("door/back"));
+            assert_eq!(auto_shutdown.state().name(), "armed");
+            rx.take(2).collect()
+        }))
+        .unwrap()
+        .into_iter()
+        .filter_map(|op| match op {
+            OpCode::Publish((_, status, _)) => Some(status),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+        assert_eq!(
+            states[0],
+            r#"{"doors":{"back":"unknown","front":"locked"},"state":"idle"}"#
+        );
+        assert_eq!(
+            states[1],
+            r#"{"doors":{"back":"locked","front":"locked"},"remaining":0,"state":"armed"}"#
+        );
+    }
+
+    #[test]
+    fn ten_minute_countdown_on_a_manual_clock() {
+        let hass = hass::FakeHass::new();
+        let clock = ManualClock::new(chrono::Utc::now());
+        let (tx, _rx) = futures::sync::mpsc::channel(16);
+        let auto_shutdown = AutoShutdown::new(
+            hass::Instances::new("default", hass.clone()),
+            "door/lock",
+            Duration::from_secs(10 * 60),
+            tx,
+            default_plan(vec![], vec![Thermostat::new("climate.lounge", 18.0)]),
+        )
+        .set_clock(Arc::new(clock.clone()))
+        .set_warning(Duration::from_secs(2 * 60));
+        let handle = auto_shutdown.clone();
+
+        // one runtime for both steps, the pending timer has to survive in between
+        let mut runtime = runtime();
+
+        let warned = {
+            let clock = clock.clone();
+            let handle = handle.clone();
+            runtime
+                .block_on(lazy(move || {
+                    auto_shutdown.handle_msg(door("1"));
+                    clock.advance(Duration::from_secs(8 * 60));
+                    settle().map(move |_| handle.state())
+                }))
+                .unwrap()
+        };
+        assert_eq!(warned.name(), "warning");
+        assert!(hass.calls().is_empty());
+
+        let off = runtime
+            .block_on(lazy(move || {
+                clock.advance(Duration::from_secs(2 * 60));
+                settle().map(move |_| handle.state())
+            }))
+            .unwrap();
+        assert_eq!(off, State::Off);
+        assert_eq!(hass.calls().len(), 1);
+    }
+
+    #[test]
+    fn payloads_are_rendered_with_the_shutdown_context() {
+        let clock = ManualClock::new(chrono::Utc.ymd(2019, 11, 2).and_hms(23, 15, 0));
+        let plan = Plan::new()
+            .add_stage(Stage::new("leds", Duration::from_secs(0)).add_message(
+                ShutdownMessage::json(
+                    "lounge/leds/set",
+                    serde_json::json!({"effect": "fade_out", "duration": 30}),
+                ),
+            ))
+            .add_stage(Stage::new("log", Duration::from_secs(30)).add_message(
+                ShutdownMessage::new(
+                    "bot/log",
+                    "{zone} at {timestamp}: {reason}, done in {plan_remaining}s",
+                ),
+            ))
+            .add_stage(
+                Stage::new("heating", Duration::from_secs(15 * 60))
+                    .add_thermostat(Thermostat::new("climate.lounge", 18.0)),
+            );
+        let (auto_shutdown, rx) = auto_shutdown_with(hass::FakeHass::new(), plan);
+        let auto_shutdown = auto_shutdown
+            .set_clock(Arc::new(clock.clone()))
+            .set_zone("lounge");
+
+        runtime()
+            .block_on(lazy(move || {
+                tokio::spawn(
+                    auto_shutdown
+                        .shutdown_futures(oneshot::channel().1)
+                        .map(|_| ()),
+                );
+                settle().and_then(move |_| {
+                    clock.advance(Duration::from_secs(30));
+                    settle()
+                })
+            }))
+            .unwrap();
+
+        let published = rx.take(2).collect().wait().unwrap();
+        assert_eq!(
+            published
+                .iter()
+                .map(|op| match op {
+                    OpCode::Publish((_, value, _)) => value.as_str(),
+                    o => panic!("unexpected message: {:?}", o),
+                })
+                .collect::<Vec<_>>(),
+            vec![
+                r#"{"duration":30,"effect":"fade_out"}"#,
+                "lounge at 2019-11-02T23:15:30Z: door locked, done in 870s",
+            ]
+        );
+    }
+
+    #[test]
+    fn unlocking_restores_the_snapshot() {
+        let hass = hass::FakeHass::new().with_state(
+            "climate.lounge",
+            "heat",
+            Some(hass::Attributes::new().set("temperature", 21.0)),
+        );
+        let (auto_shutdown, _rx) = auto_shutdown(hass.clone());
+        let auto_shutdown = auto_shutdown.set_captures(vec![Capture::setpoint("climate.lounge")]);
+
+        run_one(lazy(move || {
+            auto_shutdown.handle_msg(door("1"));
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100)).and_then(
+                move |_| {
+                    auto_shutdown.handle_msg(door("0"));
+                    tokio::timer::Delay::new(Instant::now() + Duration::from_millis(50))
+                },
+            )
+        }))
+        .unwrap();
+
+        assert_eq!(temperatures(&hass), vec![18.0, 21.0]);
+    }
+
+    #[test]
+    fn retained_command_topics_stay_retained() {
+        let clock = ManualClock::new(chrono::Utc::now());
+        let (auto_shutdown, rx) = auto_shutdown_with(
+            hass::FakeHass::new(),
+            default_plan(
+                vec![
+                    ShutdownMessage::new("lounge/relay/set", "OFF").retain(),
+                    ShutdownMessage::new("lounge/amp/set", "0"),
+                ],
+                vec![],
+            ),
+        );
+        let auto_shutdown = auto_shutdown
+            .set_clock(Arc::new(clock.clone()))
+            .set_captures(vec![
+                Capture::mqtt("lounge/relay/state", "lounge/relay/set"),
+                Capture::mqtt("lounge/amp/state", "lounge/amp/set"),
+            ])
+            .set_opening(
+                Plan::new().add_stage(
+                    Stage::new("lights", Duration::from_secs(0))
+                        .add_message(ShutdownMessage::new("lounge/relay/set", "ON")),
+                ),
+            );
+        auto_shutdown.topics.update("lounge/relay/state", "ON");
+        auto_shutdown.topics.update("lounge/amp/state", "1");
+
+        runtime()
+            .block_on(lazy(move || {
+                auto_shutdown.handle_msg(door("1"));
+                settle().and_then(move |_| {
+                    clock.advance(Duration::from_millis(10));
+                    settle().and_then(move |_| {
+                        auto_shutdown.handle_msg(door("0"));
+                        settle()
+                    })
+                })
+            }))
+            .unwrap();
+
+        let mut published = rx
+            .take(5)
+            .map(|op| match op {
+                OpCode::Publish((topic, value, delivery)) => (topic, value, delivery.retain),
+                o => panic!("unexpected message: {:?}", o),
+            })
+            .collect()
+            .wait()
+            .unwrap();
+        // the restores run concurrently
+        published[2..4].sort();
+        assert_eq!(
+            published,
+            vec![
+                ("lounge/relay/set".to_string(), "OFF".to_string(), true),
+                ("lounge/amp/set".to_string(), "0".to_string(), false),
+                ("lounge/amp/set".to_string(), "1".to_string(), false),
+                ("lounge/relay/set".to_string(), "ON".to_string(), true),
+                ("lounge/relay/set".to_string(), "ON".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn opening_plan_runs_only_after_a_completed_shutdown() {
+        let opening = Plan::new().add_stage(
+            Stage::new("heating", Duration::from_secs(0))
+                .add_thermostat(Thermostat::new("climate.lounge", 21.0)),
+        );
+        let run = |hass: hass::FakeHass, locked_for: Duration| {
+            let clock = ManualClock::new(chrono::Utc::now());
+            let (auto_shutdown, _rx) = auto_shutdown(hass);
+            let auto_shutdown = auto_shutdown
+                .set_clock(Arc::new(clock.clone()))
+                .set_opening(opening.clone());
+            runtime()
+                .block_on(lazy(move || {
+                    auto_shutdown.handle_msg(door("1"));
+                    settle().and_then(move |_| {
+                        clock.advance(locked_for);
+                        settle().and_then(move |_| {
+                            auto_shutdown.handle_msg(door("0"));
+                            settle()
+                        })
+                    })
+                }))
+                .unwrap();
+        };
+
+        let hass = hass::FakeHass::new();
+        run(hass.clone(), Duration::from_millis(10));
+        assert_eq!(temperatures(&hass), vec![18.0, 21.0]);
+
+        // unlocked while the countdown was still running
+        let hass = hass::FakeHass::new();
+        run(hass.clone(), Duration::from_millis(9));
+        assert!(hass.calls().is_empty());
+    }
+
+    #[test]
+    fn devices_turned_back_on_are_switched_off_while_locked() {
+        let clock = ManualClock::new(chrono::Utc::now());
+        let timeout = Duration::from_millis(20);
+        let (auto_shutdown, rx) = auto_shutdown_with(
+            hass::FakeHass::new(),
+            default_plan(
+                vec![
+                    ShutdownMessage::new("lounge/amp/set", "0")
+                        .confirm(Confirmation::mqtt("lounge/amp/state", "0").set_timeout(timeout)),
+                    ShutdownMessage::new("lounge/printer/set", "0").confirm(
+                        Confirmation::mqtt("lounge/printer/state", "0").set_timeout(timeout),
+                    ),
+                ],
+                vec![],
+            ),
+        );
+        let auto_shutdown = auto_shutdown
+            .set_clock(Arc::new(clock.clone()))
+            .set_enforcement(true)
+            .set_exemptions(vec!["lounge/printer/set".to_string()]);
+        let state = |topic: &str, value: &str| {
+            OpCode::MessageReceived((topic.to_string(), value.to_string()))
+        };
+
+        let off = runtime()
+            .block_on(lazy(move || {
+                // nothing is enforced before the shutdown ran
+                auto_shutdown.handle_msg(state("lounge/amp/state", "1"));
+                auto_shutdown.handle_msg(state("lounge/printer/state", "0"));
+                auto_shutdown.handle_msg(door("1"));
+                settle().and_then(move |_| {
+                    clock.advance(Duration::from_millis(10));
+                    settle().and_then(move |_| {
+                        // the amp follows on the next look at its state
+                        auto_shutdown.handle_msg(state("lounge/amp/state", "0"));
+                        clock.advance(Duration::from_millis(2));
+                        settle().and_then(move |_| {
+                            let off = auto_shutdown.state();
+                            // someone turns the amp and the printer back on
+                            auto_shutdown.handle_msg(state("lounge/amp/state", "1"));
+                            auto_shutdown.handle_msg(state("lounge/amp/state", "1"));
+                            auto_shutdown.handle_msg(state("lounge/printer/state", "1"));
+                            settle().map(move |_| off)
+                        })
+                    })
+                })
+            }))
+            .unwrap();
+        assert_eq!(off, State::Off);
+
+        let published = rx
+            .take(3)
+            .map(|op| match op {
+                OpCode::Publish((topic, _, _)) => topic,
+                o => panic!("unexpected message: {:?}", o),
+            })
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            published,
+            vec!["lounge/amp/set", "lounge/printer/set", "lounge/amp/set"]
+        );
+    }
+
+    #[test]
+    fn very_long_countdowns_are_saved() {
+        let path = std::env::temp_dir().join(format!("shutdown-long-{}.json", std::process::id()));
+        let clock = ManualClock::new(chrono::Utc::now());
+        let (auto_shutdown, _rx) = auto_shutdown(hass::FakeHass::new());
+        let auto_shutdown = auto_shutdown
+            .set_clock(Arc::new(clock.clone()))
+            .set_state_file(path.to_str().unwrap());
+
+        // more than a `chrono::Duration` can hold
+        let now = clock.now();
+        auto_shutdown.save(
+            State::Armed {
+                deadline: now + Duration::from_secs(10_000_000_000_000),
+            },
+            now,
+        );
+        let state_file = StateFile::new(&path);
+        match state_file.load() {
+            Some(Saved::Armed { deadline, .. }) => assert_eq!(
+                deadline,
+                clock.utc_now() + chrono::Duration::from_std(MAX_SAVED_DELAY).unwrap()
+            ),
+            o => panic!("unexpected state: {:?}", o),
+        }
+        state_file.save(None);
+    }
+
+    #[test]
+    fn countdown_survives_a_restart() {
+        let path =
+            std::env::temp_dir().join(format!("shutdown-resume-{}.json", std::process::id()));
+        let clock = ManualClock::new(chrono::Utc::now());
+        let start = |hass: hass::FakeHass| {
+            let (auto_shutdown, _rx) = auto_shutdown(hass);
+            auto_shutdown
+                .set_clock(Arc::new(clock.clone()))
+                .set_state_file(path.to_str().unwrap())
+        };
+
+        // the pending timer goes away with the runtime, like with a restart
+        let before = start(hass::FakeHass::new());
+        runtime()
+            .block_on(lazy(move || {
+                before.handle_msg(door("1"));
+                settle()
+            }))
+            .unwrap();
+        assert!(path.exists());
+
+        // the daemon was down for longer than the delay
+        clock.advance(Duration::from_secs(60));
+        let hass = hass::FakeHass::new();
+        let after = start(hass.clone());
+        let handle = after.clone();
+        let state = runtime()
+            .block_on(lazy(move || {
+                after.resume();
+                settle().map(move |_| handle.state())
+            }))
+            .unwrap();
+        assert_eq!(state, State::Off);
+        assert_eq!(hass.calls().len(), 1);
+        let state_file = StateFile::new(&path);
+        assert_eq!(
+            state_file.load(),
+            Some(Saved::Off {
+                snapshot: Some(Snapshot::default())
+            })
+        );
+        state_file.save(None);
+    }
+
+    #[test]
+    fn resumed_snapshot_is_restored_when_unlocked_before_the_shutdown() {
+        let path =
+            std::env::temp_dir().join(format!("shutdown-resumed-{}.json", std::process::id()));
+        let clock = ManualClock::new(chrono::Utc::now());
+        StateFile::new(&path).save(Some(&Saved::Armed {
+            deadline: clock.utc_now(),
+            snapshot: Some(Snapshot {
+                values: vec![(Capture::setpoint("climate.lounge"), "21".to_string())],
+            }),
+        }));
+        let hass = hass::FakeHass::new().with_state(
+            "climate.lounge",
+            "heat",
+            Some(hass::Attributes::new().set("temperature", 18.0)),
+        );
+        let (auto_shutdown, _rx) = auto_shutdown(hass.clone());
+        let auto_shutdown = auto_shutdown
+            .set_clock(Arc::new(clock.clone()))
+            .set_state_file(path.to_str().unwrap())
+            .set_captures(vec![Capture::setpoint("climate.lounge")]);
+
+        runtime()
+            .block_on(lazy(move || {
+                auto_shutdown.resume();
+                // the retained lock state says the door was opened in the meantime
+                auto_shutdown.handle_msg(door("0"));
+                settle().and_then(move |_| {
+                    // the next shutdown takes a snapshot of its own
+                    auto_shutdown.handle_msg(door("1"));
+                    settle().and_then(move |_| {
+                        clock.advance(Duration::from_millis(10));
+                        settle().and_then(move |_| {
+                            auto_shutdown.handle_msg(door("0"));
+                            settle()
+                        })
+                    })
+                })
+            }))
+            .unwrap();
+
+        assert_eq!(temperatures(&hass), vec![21.0, 18.0, 18.0]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn snapshot_survives_a_restart() {
+        let path =
+            std::env::temp_dir().join(format!("shutdown-snapshot-{}.json", std::process::id()));
+        let clock = ManualClock::new(chrono::Utc::now());
+        let start = |temperature: f64| {
+            let hass = hass::FakeHass::new().with_state(
+                "climate.lounge",
+                "heat",
+                Some(hass::Attributes::new().set("temperature", temperature)),
+            );
+            let (auto_shutdown, _rx) = auto_shutdown(hass.clone());
+            let auto_shutdown = auto_shutdown
+                .set_clock(Arc::new(clock.clone()))
+                .set_state_file(path.to_str().unwrap())
+                .set_captures(vec![Capture::setpoint("climate.lounge")]);
+            (auto_shutdown, hass)
+        };
+
+        let (before, _) = start(21.0);
+        let advance = clock.clone();
+        runtime()
+            .block_on(lazy(move || {
+                before.handle_msg(door("1"));
+                settle().and_then(move |_| {
+                    advance.advance(Duration::from_millis(10));
+                    settle()
+                })
+            }))
+            .unwrap();
+
+        // the heating is down to 18 degrees after the restart
+        let (after, hass) = start(18.0);
+        runtime()
+            .block_on(lazy(move || {
+                after.resume();
+                after.handle_msg(door("0"));
+                settle()
+            }))
+            .unwrap();
+        assert_eq!(temperatures(&hass), vec![21.0]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn lock_state_is_reconciled_with_home_assistant() {
+        let reconciled = |lock_state: &str| {
+            let hass = hass::FakeHass::new().with_state("lock.door", lock_state, None);
+            let (auto_shutdown, _rx) = auto_shutdown(hass);
+            let auto_shutdown = auto_shutdown.set_lock_entity("lock.door", "locked");
+            run_one(lazy(move || {
+                auto_shutdown.handle_msg(OpCode::RetainedReceived((
+                    "door/lock".to_string(),
+                    "1".to_string(),
+                )));
+                auto_shutdown
+                    .reconcile()
+                    .map(move |_| auto_shutdown.state())
+            }))
+            .unwrap()
+        };
+
+        // the retained message is outdated, the door has been opened since
+        assert_eq!(reconciled("unlocked"), State::Idle);
+        assert_eq!(reconciled("locked").name(), "armed");
+    }
+
+    #[test]
+    fn retained_lock_state_does_not_run_the_shutdown_twice() {
+        let path = std::env::temp_dir().join(format!("shutdown-off-{}.json", std::process::id()));
+        let first = hass::FakeHass::new();
+        let (before, _rx) = auto_shutdown(first.clone());
+        let before = before.set_state_file(path.to_str().unwrap());
+        run_one(lazy(move || {
+            before.handle_msg(door("1"));
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+        }))
+        .unwrap();
+        assert_eq!(first.calls().len(), 1);
+
+        let second = hass::FakeHass::new();
+        let (after, _rx) = auto_shutdown(second.clone());
+        let after = after.set_state_file(path.to_str().unwrap());
+        let handle = after.clone();
+        let state = run_one(lazy(move || {
+            after.resume();
+            after.handle_msg(OpCode::RetainedReceived((
+                "door/lock".to_string(),
+                "1".to_string(),
+            )));
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+                .map(move |_| handle.state())
+        }))
+        .unwrap();
+        assert_eq!(state, State::Off);
+        assert!(second.calls().is_empty());
+        StateFile::new(&path).save(None);
+    }
+
+    #[test]
+    fn door_has_to_stay_locked_before_the_countdown_starts() {
+        let clock = ManualClock::new(chrono::Utc::now());
+        let (auto_shutdown, _rx) = auto_shutdown(hass::FakeHass::new());
+        let auto_shutdown = auto_shutdown
+            .set_clock(Arc::new(clock.clone()))
+            .set_debounce(Debounce::new(
+                Duration::from_secs(5),
+                Duration::from_secs(5),
+            ));
+        let handle = auto_shutdown.clone();
+        let mut runtime = runtime();
+
+        let flapping = {
+            let clock = clock.clone();
+            let handle = handle.clone();
+            runtime
+                .block_on(lazy(move || {
+                    for value in &["1", "0", "1"] {
+                        auto_shutdown.handle_msg(door(value));
+                        clock.advance(Duration::from_secs(2));
+                    }
+                    settle().map(move |_| handle.state())
+                }))
+                .unwrap()
+        };
+        assert_eq!(flapping, State::Idle);
+
+        let locked = runtime
+            .block_on(lazy(move || {
+                clock.advance(Duration::from_secs(3));
+                settle().map(move |_| handle.state())
+            }))
+            .unwrap();
+        assert_eq!(locked.name(), "armed");
+    }
+
+    #[test]
+    fn unlocking_the_door_stops_the_timer() {
+        let hass = hass::FakeHass::new();
+        let (auto_shutdown, _rx) = auto_shutdown(hass.clone());
+
+        run_one(lazy(move || {
+            auto_shutdown.handle_msg(door("1"));
+            auto_shutdown.handle_msg(door("0"));
+            tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+        }))
+        .unwrap();
+
+        assert!(hass.calls().is_empty());
+    }
+}
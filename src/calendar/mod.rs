@@ -1,3 +1,6 @@
+use chrono::{DateTime, Utc};
+use futures::future::Either;
+use futures::Future;
 use libical_sys::{
     icalcomponent, icalcomponent_free, icalcomponent_kind_ICAL_ANY_COMPONENT as ICAL_ANY_COMPONENT,
     icalcomponent_kind_ICAL_VEVENT_COMPONENT as ICAL_VEVENT_COMPONENT, icalparser_parse_string,
@@ -7,14 +10,24 @@ use libical_sys::{
     icalproperty_kind_ICAL_RRULE_PROPERTY as ICAL_RRULE_PROPERTY,
 };
 use std::ffi::{CStr, CString};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::Clock;
 
 mod event;
 
-use event::Event;
+pub use event::Event;
+
+// How far ahead recurring events are expanded when looking for the next one.
+const HORIZON_DAYS: i64 = 30;
+
+// A calendar server that does not answer must not hold up the stage that asks it.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
 
 trait Calendar {
-    fn get_current_event(&self);
-    fn get_next_event(&self);
+    fn get_current_event(&self, clock: &dyn Clock) -> Option<Event>;
+    fn get_next_event(&self, clock: &dyn Clock) -> Option<Event>;
 }
 
 #[derive(Debug)]
@@ -63,8 +76,14 @@ impl Ical {
         self.into_iter()
     }
 
-    fn print_events(&mut self) {
-        let now = std::time::SystemTime::now();
+    /// Like `iter`, but recurrences starting after `until` are not expanded, so an event that
+    /// repeats forever does not keep the iterator running forever.
+    fn iter_until(&self, until: DateTime<Utc>) -> IcalIterator {
+        IcalIterator::new(self).until(until)
+    }
+
+    fn print_events(&mut self, clock: &dyn Clock) {
+        let now: std::time::SystemTime = clock.utc_now().into();
 
         let mut it: libical_sys::icalcompiter = unsafe {
             libical_sys::icalcomponent_begin_component(self.calendar, ICAL_VEVENT_COMPONENT)
@@ -126,6 +145,59 @@ impl Ical {
     }
 }
 
+impl Calendar for Ical {
+    fn get_current_event(&self, clock: &dyn Clock) -> Option<Event> {
+        current_events(self, clock).into_iter().next()
+    }
+
+    fn get_next_event(&self, clock: &dyn Clock) -> Option<Event> {
+        let now = clock.utc_now();
+        self.iter_until(now + chrono::Duration::days(HORIZON_DAYS))
+            .filter(|e| e.start > now)
+            .min_by_key(|e| e.start)
+    }
+}
+
+fn current_events(ical: &Ical, clock: &dyn Clock) -> Vec<Event> {
+    let now = clock.utc_now();
+    ical.iter_until(now)
+        .filter(|e| e.start <= now && now < e.end)
+        .collect()
+}
+
+/// Downloads the calendar at `url` and returns the events going on right now. Fails if the
+/// server has not answered within `FETCH_TIMEOUT`.
+pub fn fetch_current_events(
+    url: &str,
+    clock: &Arc<dyn Clock>,
+) -> Box<dyn Future<Item = Vec<Event>, Error = String> + Send> {
+    let client = match reqwest::r#async::ClientBuilder::new()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => return Box::new(futures::future::err(e.to_string())),
+    };
+    let deadline = clock.delay_until(clock.now() + FETCH_TIMEOUT);
+    let clock = Arc::clone(clock);
+    let fetch = client
+        .get(url)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|mut r| r.text())
+        .map_err(|e| e.to_string())
+        .and_then(move |text| {
+            let ical = Ical::new_from_str(text).map_err(|e| format!("{:?}", e))?;
+            Ok(current_events(&ical, &*clock))
+        });
+    Box::new(fetch.select2(deadline).then(|r| match r {
+        Ok(Either::A((events, _))) => Ok(events),
+        Ok(Either::B(_)) => Err(format!("no answer within {}s", FETCH_TIMEOUT.as_secs())),
+        Err(Either::A((e, _))) => Err(e),
+        Err(Either::B(_)) => Err("the timer failed".to_string()),
+    }))
+}
+
 impl<'a> IntoIterator for &'a Ical {
     type Item = Event;
     type IntoIter = IcalIterator<'a>;
@@ -152,10 +224,11 @@ impl Drop for IcalIterVeventState {
 struct IcalIterVevent {
     component: *mut libical_sys::icalcomponent,
     ritr: IcalIterVeventState,
+    until: Option<DateTime<Utc>>,
 }
 
 impl IcalIterVevent {
-    fn new(component: *mut libical_sys::icalcomponent) -> Self {
+    fn new(component: *mut libical_sys::icalcomponent, until: Option<DateTime<Utc>>) -> Self {
         let rrule = unsafe {
             libical_sys::icalcomponent_get_first_property(component, ICAL_RRULE_PROPERTY)
         };
@@ -171,7 +244,11 @@ impl IcalIterVevent {
         } else {
             IcalIterVeventState::NoRecur
         };
-        Self { component, ritr }
+        Self {
+            component,
+            ritr,
+            until,
+        }
     }
 }
 
@@ -211,7 +288,13 @@ impl Iterator for IcalIterVevent {
                     self.next()
                 } else {
                     let time = unsafe { libical_sys::icaltime_as_timet(item) };
-                    Some(event.starting_at(time))
+                    let event = event.starting_at(time);
+                    if self.until.map_or(false, |until| event.start > until) {
+                        self.ritr = IcalIterVeventState::Done;
+                        None
+                    } else {
+                        Some(event)
+                    }
                 }
             }
         }
@@ -227,6 +310,7 @@ struct IcalIterator<'a> {
     _ical: &'a Ical, // bind our lifetime to the lifetime of the actual ical instance
     vevent_iterator: libical_sys::icalcompiter,
     state: Option<IterState>,
+    until: Option<DateTime<Utc>>,
 }
 
 impl<'a> IcalIterator<'a> {
@@ -239,16 +323,22 @@ impl<'a> IcalIterator<'a> {
             _ical: ical,
             vevent_iterator,
             state: None,
+            until: None,
         }
     }
 
+    fn until(mut self, until: DateTime<Utc>) -> Self {
+        self.until = Some(until);
+        self
+    }
+
     fn next_vevent(&mut self) -> Option<IcalIterVevent> {
         let item = unsafe { libical_sys::icalcompiter_next(&mut self.vevent_iterator) };
         let next = unsafe { libical_sys::icalcompiter_deref(&mut self.vevent_iterator) };
         if next == 0 as _ {
             None
         } else {
-            Some(IcalIterVevent::new(item))
+            Some(IcalIterVevent::new(item, self.until))
         }
     }
 }
@@ -297,6 +387,7 @@ fn convert_timet(time: libical_sys::time_t) -> std::time::SystemTime {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::{ManualClock, SystemClock};
     use futures::Future;
     use futures::IntoFuture;
 
@@ -311,6 +402,23 @@ mod tests {
         runtime.block_on(f.into_future())
     }
 
+    #[test]
+    fn fetching_gives_up_on_a_silent_server() {
+        // accepts the connection and never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/calendar.ics", listener.local_addr().unwrap());
+        let clock = ManualClock::new(chrono::Utc::now());
+        let dyn_clock: Arc<dyn Clock> = Arc::new(clock.clone());
+
+        let result = run_one(futures::future::lazy(move || {
+            let fetch = fetch_current_events(&url, &dyn_clock);
+            clock.advance(FETCH_TIMEOUT);
+            fetch
+        }));
+        assert_eq!(result.err(), Some("no answer within 30s".to_string()));
+        drop(listener);
+    }
+
     #[test]
     fn test_ical_decode() {
         let client = reqwest::r#async::ClientBuilder::new().build().unwrap();
@@ -320,7 +428,7 @@ mod tests {
             .and_then(|mut r| r.text());
         let text = run_one(fut).unwrap();
         let mut ical = Ical::new_from_str(text).unwrap();
-        ical.print_events();
+        ical.print_events(&SystemClock);
     }
 
     #[test]
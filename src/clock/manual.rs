@@ -0,0 +1,113 @@
+use super::Clock;
+use chrono::{DateTime, Utc};
+use futures::sync::oneshot;
+use futures::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct ManualClockInner {
+    now: Instant,
+    utc_now: DateTime<Utc>,
+    timers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+/// A clock that stands still until it is advanced. Timers fire during `advance` once their
+/// deadline has been reached.
+#[derive(Clone)]
+pub struct ManualClock {
+    inner: Arc<Mutex<ManualClockInner>>,
+}
+
+impl ManualClock {
+    pub fn new(utc_now: DateTime<Utc>) -> Self {
+        ManualClock {
+            inner: Arc::new(Mutex::new(ManualClockInner {
+                now: Instant::now(),
+                utc_now,
+                timers: vec![],
+            })),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut inner = self.inner.lock().expect("Mutex poisoned");
+        inner.now += by;
+        inner.utc_now = inner.utc_now + chrono::Duration::from_std(by).expect("duration too long");
+
+        let now = inner.now;
+        let (due, pending) = inner
+            .timers
+            .drain(..)
+            .filter(|(_, sender)| !sender.is_canceled())
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+        inner.timers = pending;
+        for (_, sender) in due {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Number of timers that have not fired yet and are still being waited for.
+    pub fn pending_timers(&self) -> usize {
+        let inner = self.inner.lock().expect("Mutex poisoned");
+        inner
+            .timers
+            .iter()
+            .filter(|(_, sender)| !sender.is_canceled())
+            .count()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().expect("Mutex poisoned").now
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        self.inner.lock().expect("Mutex poisoned").utc_now
+    }
+
+    fn delay_until(&self, deadline: Instant) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut inner = self.inner.lock().expect("Mutex poisoned");
+        if deadline <= inner.now {
+            return Box::new(futures::future::ok(()));
+        }
+        let (sender, receiver) = oneshot::channel();
+        inner.timers.push((deadline, sender));
+        Box::new(receiver.map_err(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_fires_due_timers() {
+        let start: DateTime<Utc> = "2019-10-18T20:00:00Z".parse().unwrap();
+        let clock = ManualClock::new(start);
+        let t0 = clock.now();
+
+        let early = clock.delay_until(t0 + Duration::from_secs(60));
+        let late = clock.delay_until(t0 + Duration::from_secs(600));
+        assert_eq!(clock.pending_timers(), 2);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), t0 + Duration::from_secs(60));
+        assert_eq!(clock.utc_now(), start + chrono::Duration::minutes(1));
+        assert_eq!(clock.pending_timers(), 1);
+        assert!(early.wait().is_ok());
+
+        clock.advance(Duration::from_secs(540));
+        assert_eq!(clock.pending_timers(), 0);
+        assert!(late.wait().is_ok());
+    }
+
+    #[test]
+    fn dropped_timers_are_forgotten() {
+        let clock = ManualClock::new(Utc::now());
+        let timer = clock.delay_until(clock.now() + Duration::from_secs(1));
+        drop(timer);
+        assert_eq!(clock.pending_timers(), 0);
+        assert!(clock.delay_until(clock.now()).wait().is_ok());
+    }
+}
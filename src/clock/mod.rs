@@ -0,0 +1,36 @@
+use chrono::{DateTime, Utc};
+use futures::Future;
+use std::time::Instant;
+
+#[cfg(test)]
+mod manual;
+
+#[cfg(test)]
+pub use manual::ManualClock;
+
+/// Source of monotonic time, wall-clock time and timers. Everything that waits or looks at the
+/// time goes through this so tests can run a ten minute countdown without waiting for it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn utc_now(&self) -> DateTime<Utc>;
+    /// Resolves once `now()` has reached `deadline`.
+    fn delay_until(&self, deadline: Instant) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+}
+
+/// The real time, with timers on the tokio runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn delay_until(&self, deadline: Instant) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(tokio::timer::Delay::new(deadline).map_err(|e| println!("timer failed: {}", e)))
+    }
+}
@@ -0,0 +1,386 @@
+use crate::calendar;
+use crate::clock::Clock;
+use crate::hass::{self, Hass, State};
+use crate::mqtt::TopicStates;
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use futures::Future;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Above(f64),
+    Below(f64),
+}
+
+impl Comparison {
+    fn matches(self, value: f64) -> bool {
+        match self {
+            Comparison::Above(threshold) => value > threshold,
+            Comparison::Below(threshold) => value < threshold,
+        }
+    }
+}
+
+/// A daily period in local time. It may span midnight, e.g. from 17:00 until 08:00.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    from: NaiveTime,
+    until: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn new(from: NaiveTime, until: NaiveTime) -> Self {
+        TimeWindow { from, until }
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.until {
+            self.from <= time && time < self.until
+        } else {
+            self.from <= time || time < self.until
+        }
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} to {}",
+            self.from.format("%H:%M"),
+            self.until.format("%H:%M")
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Hass(hass::Error),
+    Calendar(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Hass(e) => write!(f, "{}", e),
+            Error::Calendar(e) => write!(f, "failed to read the calendar: {}", e),
+        }
+    }
+}
+
+/// What a condition can look at. Home Assistant conditions are evaluated by `hass`, the
+/// instance of the action they gate, or the default one for vetoes.
+pub struct Context<'a, H> {
+    pub hass: &'a H,
+    pub topics: &'a TopicStates,
+    pub clock: &'a Arc<dyn Clock>,
+}
+
+/// A condition on the state of the space. The more involved ones are evaluated by Home
+/// Assistant, so the logic we already keep there does not have to be duplicated here.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    // A Jinja template, true if it renders to something like `true`, `on` or `1`.
+    Template(String),
+    // The time weighted average of a numeric sensor over the last `window`.
+    Average {
+        entity: String,
+        window: Duration,
+        comparison: Comparison,
+    },
+    // The current state of an entity.
+    State {
+        entity: String,
+        state: String,
+    },
+    // The last value seen on a subscribed topic.
+    Mqtt {
+        topic: String,
+        value: String,
+    },
+    // The local time of day.
+    TimeOfDay(TimeWindow),
+    // The local day of the week.
+    Weekday(Vec<Weekday>),
+    // An event in the calendar at `url` is going on, if given one whose summary contains
+    // `summary`.
+    CalendarEvent {
+        url: String,
+        summary: Option<String>,
+    },
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    pub fn not(condition: Condition) -> Self {
+        Condition::Not(Box::new(condition))
+    }
+
+    /// The MQTT topic the condition needs to be subscribed to, if any.
+    pub fn topic(&self) -> Option<&str> {
+        match self {
+            Condition::Mqtt { topic, .. } => Some(topic),
+            Condition::Not(condition) => condition.topic(),
+            _ => None,
+        }
+    }
+
+    pub fn evaluate<H: Hass>(
+        &self,
+        context: &Context<H>,
+    ) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+        let hass = context.hass;
+        let now = context.clock.utc_now();
+        let local = now.with_timezone(&chrono::Local);
+        match self {
+            Condition::Template(template) => Box::new(
+                hass.render_template(template)
+                    .map(|r| is_truthy(&r))
+                    .map_err(Error::Hass),
+            ),
+            Condition::State { entity, state } => {
+                let expected = state.clone();
+                Box::new(
+                    hass.get_state(entity)
+                        .map(move |s| s.name == expected)
+                        .map_err(Error::Hass),
+                )
+            }
+            Condition::Mqtt { topic, value } => Box::new(futures::future::ok(
+                context.topics.get(topic).as_ref() == Some(value),
+            )),
+            Condition::TimeOfDay(window) => {
+                Box::new(futures::future::ok(window.contains(local.time())))
+            }
+            Condition::Weekday(days) => {
+                Box::new(futures::future::ok(days.contains(&local.weekday())))
+            }
+            Condition::CalendarEvent { url, summary } => {
+                let summary = summary.clone();
+                Box::new(
+                    calendar::fetch_current_events(url, context.clock)
+                        .map(move |events| {
+                            events.iter().any(|e| match &summary {
+                                Some(summary) => e.summary.contains(summary.as_str()),
+                                None => true,
+                            })
+                        })
+                        .map_err(Error::Calendar),
+                )
+            }
+            Condition::Not(condition) => Box::new(condition.evaluate(context).map(|b| !b)),
+            Condition::Average {
+                entity,
+                window,
+                comparison,
+            } => {
+                let window = chrono::Duration::from_std(*window)
+                    .unwrap_or_else(|_| chrono::Duration::zero());
+                let start = now - window;
+                let comparison = *comparison;
+                Box::new(
+                    hass.get_history(entity, start, now)
+                        .map(move |states| {
+                            time_weighted_average(&states, start, now)
+                                .map(|avg| comparison.matches(avg))
+                                .unwrap_or(false)
+                        })
+                        .map_err(Error::Hass),
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Template(template) => write!(f, "template {}", template),
+            Condition::Average {
+                entity,
+                window,
+                comparison,
+            } => {
+                write!(f, "average of {} over {:?} ", entity, window)?;
+                match comparison {
+                    Comparison::Above(v) => write!(f, "> {}", v),
+                    Comparison::Below(v) => write!(f, "< {}", v),
+                }
+            }
+            Condition::State { entity, state } => write!(f, "{} is {}", entity, state),
+            Condition::Mqtt { topic, value } => write!(f, "{} is {}", topic, value),
+            Condition::TimeOfDay(window) => write!(f, "between {}", window),
+            Condition::Weekday(days) => {
+                let days = days.iter().map(|d| format!("{:?}", d)).collect::<Vec<_>>();
+                write!(f, "on {}", days.join(", "))
+            }
+            Condition::CalendarEvent { url, summary: None } => write!(f, "event in {}", url),
+            Condition::CalendarEvent {
+                url,
+                summary: Some(summary),
+            } => write!(f, "event {} in {}", summary, url),
+            Condition::Not(condition) => write!(f, "not {}", condition),
+        }
+    }
+}
+
+fn is_truthy(rendered: &str) -> bool {
+    match rendered.trim().to_lowercase().as_str() {
+        "true" | "on" | "yes" | "1" => true,
+        _ => false,
+    }
+}
+
+/// Averages the numeric states over `[start, end]`, weighting each by how long it was held.
+/// States that are not numbers (`unavailable`, `unknown`) are left out.
+fn time_weighted_average(
+    states: &[State],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Option<f64> {
+    let mut weighted_sum = 0.0;
+    let mut total = 0.0;
+
+    for (i, state) in states.iter().enumerate() {
+        let from = state.last_changed.unwrap_or(start).max(start);
+        let until = states
+            .get(i + 1)
+            .and_then(|s| s.last_changed)
+            .unwrap_or(end)
+            .min(end);
+        if until <= from {
+            continue;
+        }
+        if let Ok(value) = state.name.parse::<f64>() {
+            let secs = (until - from).num_milliseconds() as f64 / 1000.0;
+            weighted_sum += value * secs;
+            total += secs;
+        }
+    }
+
+    if total > 0.0 {
+        Some(weighted_sum / total)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::hass::FakeHass;
+
+    fn evaluate(condition: &Condition, hass: &FakeHass, topics: &TopicStates) -> bool {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let context = Context {
+            hass,
+            topics,
+            clock: &clock,
+        };
+        condition.evaluate(&context).wait().unwrap()
+    }
+
+    fn state(value: &str, at: DateTime<Utc>) -> State {
+        let mut state = State::new(value, None);
+        state.last_changed = Some(at);
+        state
+    }
+
+    #[test]
+    fn average_is_weighted_by_duration() {
+        let start: DateTime<Utc> = "2019-10-18T20:00:00Z".parse().unwrap();
+        let end = start + chrono::Duration::minutes(10);
+        let states = vec![
+            // changed before the window started, counts from `start`
+            state("100", start - chrono::Duration::minutes(30)),
+            state("unavailable", start + chrono::Duration::minutes(2)),
+            state("10", start + chrono::Duration::minutes(4)),
+        ];
+        // 2 minutes at 100 and 6 minutes at 10
+        let avg = time_weighted_average(&states, start, end).unwrap();
+        assert!((avg - 32.5).abs() < 1e-9);
+        assert_eq!(time_weighted_average(&[], start, end), None);
+    }
+
+    #[test]
+    fn time_window_across_midnight() {
+        let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let evening = TimeWindow::new(at(17, 0), at(8, 0));
+        assert!(evening.contains(at(23, 30)));
+        assert!(evening.contains(at(2, 0)));
+        assert!(!evening.contains(at(8, 0)));
+        assert!(!evening.contains(at(12, 0)));
+
+        let day = TimeWindow::new(at(8, 0), at(17, 0));
+        assert!(day.contains(at(12, 0)));
+        assert!(!day.contains(at(17, 0)));
+        assert_eq!(evening.to_string(), "17:00 to 08:00");
+    }
+
+    #[test]
+    fn template_condition() {
+        let hass = FakeHass::new()
+            .with_template("{{ is_state('sensor.printer_state', 'printing') }}", "True");
+        let topics = TopicStates::new();
+
+        let printing =
+            Condition::Template("{{ is_state('sensor.printer_state', 'printing') }}".to_string());
+        assert!(evaluate(&printing, &hass, &topics));
+        let unknown = Condition::Template("{{ false }}".to_string());
+        assert!(!evaluate(&unknown, &hass, &topics));
+    }
+
+    #[test]
+    fn average_condition() {
+        let now = Utc::now();
+        let hass = FakeHass::new().with_history(
+            "sensor.lounge_power",
+            vec![state("80", now - chrono::Duration::minutes(20))],
+        );
+        let condition = Condition::Average {
+            entity: "sensor.lounge_power".to_string(),
+            window: Duration::from_secs(10 * 60),
+            comparison: Comparison::Above(50.0),
+        };
+        assert!(evaluate(&condition, &hass, &TopicStates::new()));
+    }
+
+    #[test]
+    fn state_conditions() {
+        let hass = FakeHass::new().with_state("sensor.printer_state", "printing", None);
+        let topics = TopicStates::new();
+        topics.update("lounge/amp/state", "1");
+
+        let printing = Condition::State {
+            entity: "sensor.printer_state".to_string(),
+            state: "printing".to_string(),
+        };
+        assert!(evaluate(&printing, &hass, &topics));
+        assert!(!evaluate(&Condition::not(printing), &hass, &topics));
+
+        let amp_on = Condition::Mqtt {
+            topic: "lounge/amp/state".to_string(),
+            value: "1".to_string(),
+        };
+        assert_eq!(amp_on.topic(), Some("lounge/amp/state"));
+        assert!(evaluate(&amp_on, &hass, &topics));
+        topics.update("lounge/amp/state", "0");
+        assert!(!evaluate(&amp_on, &hass, &topics));
+    }
+
+    #[test]
+    fn weekday_condition() {
+        let today = chrono::Local::now().weekday();
+        let hass = FakeHass::new();
+        let topics = TopicStates::new();
+        assert!(evaluate(&Condition::Weekday(vec![today]), &hass, &topics));
+        assert!(!evaluate(
+            &Condition::Weekday(vec![today.succ()]),
+            &hass,
+            &topics
+        ));
+    }
+}
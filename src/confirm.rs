@@ -0,0 +1,260 @@
+use crate::clock::Clock;
+use crate::hass::{Hass, Instances};
+use crate::mqtt::TopicStates;
+use futures::future::{self, Future, Loop};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the actual state of a device can be read back from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    // A state topic the device publishes on, e.g. `w17/lounge/amp/state`.
+    Mqtt(String),
+    // The state of an entity, read with `get_state`.
+    Hass {
+        entity: String,
+        instance: Option<String>,
+    },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Mqtt(topic) => write!(f, "{}", topic),
+            Source::Hass {
+                entity,
+                instance: None,
+            } => write!(f, "{}", entity),
+            Source::Hass {
+                entity,
+                instance: Some(instance),
+            } => write!(f, "{} on {}", entity, instance),
+        }
+    }
+}
+
+/// How to tell that an action took effect. Unconfirmed actions are issued again, up to
+/// `attempts` times in total.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Confirmation {
+    source: Source,
+    expected: String,
+    // Where the value is found in a JSON payload on an MQTT topic.
+    pointer: Option<String>,
+    timeout: Duration,
+    attempts: u32,
+}
+
+impl Confirmation {
+    pub fn mqtt(topic: &str, expected: &str) -> Self {
+        Confirmation::new(Source::Mqtt(topic.to_string()), expected)
+    }
+
+    pub fn hass(entity: &str, expected: &str) -> Self {
+        Confirmation::new(
+            Source::Hass {
+                entity: entity.to_string(),
+                instance: None,
+            },
+            expected,
+        )
+    }
+
+    fn new(source: Source, expected: &str) -> Self {
+        Confirmation {
+            source,
+            expected: expected.to_string(),
+            pointer: None,
+            timeout: Duration::from_secs(10),
+            attempts: 3,
+        }
+    }
+
+    /// Reads the entity from the named Home Assistant instance instead of the default one.
+    pub fn on(mut self, instance: &str) -> Self {
+        if let Source::Hass { entity, .. } = self.source {
+            self.source = Source::Hass {
+                entity,
+                instance: Some(instance.to_string()),
+            };
+        }
+        self
+    }
+
+    /// Takes the value from a JSON payload at the given JSON pointer, e.g. `/state` for the
+    /// state Zigbee2MQTT publishes.
+    pub fn set_pointer(mut self, pointer: &str) -> Self {
+        self.pointer = Some(pointer.to_string());
+        self
+    }
+
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn set_attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    pub fn expected(&self) -> &str {
+        &self.expected
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// The value in a message on the MQTT source, `None` if it has no value at the pointer.
+    pub fn value(&self, payload: &str) -> Option<String> {
+        let pointer = match &self.pointer {
+            Some(pointer) => pointer,
+            None => return Some(payload.to_string()),
+        };
+        let json: serde_json::Value = serde_json::from_str(payload).ok()?;
+        match json.pointer(pointer)? {
+            serde_json::Value::String(value) => Some(value.clone()),
+            value => Some(value.to_string()),
+        }
+    }
+
+    /// Waits until the source shows the expected value, or the timeout passed. The error holds
+    /// what was seen instead.
+    pub fn wait<H: Hass + Send + Sync + 'static>(
+        &self,
+        hass: &Instances<H>,
+        topics: &TopicStates,
+        clock: &Arc<dyn Clock>,
+    ) -> Box<dyn Future<Item = Result<(), String>, Error = ()> + Send> {
+        let deadline = clock.now() + self.timeout;
+        let interval = POLL_INTERVAL.min(self.timeout / 10);
+        let this = self.clone();
+        let hass = hass.clone();
+        let topics = topics.clone();
+        let clock = Arc::clone(clock);
+
+        Box::new(future::loop_fn((), move |_| {
+            let confirmation = this.clone();
+            let expected = this.expected.clone();
+            let clock = Arc::clone(&clock);
+            read(&this.source, &hass, &topics).and_then(move |seen| {
+                let seen = seen.and_then(|payload| confirmation.value(&payload));
+                if seen.as_ref() == Some(&expected) {
+                    return future::Either::A(future::ok(Loop::Break(Ok(()))));
+                }
+                let now = clock.now();
+                if now >= deadline {
+                    let seen = seen.unwrap_or_else(|| "nothing".to_string());
+                    return future::Either::A(future::ok(Loop::Break(Err(format!(
+                        "expected {}, got {}",
+                        expected, seen
+                    )))));
+                }
+                future::Either::B(
+                    clock
+                        .delay_until((now + interval).min(deadline))
+                        .map(|_| Loop::Continue(())),
+                )
+            })
+        }))
+    }
+}
+
+/// The current value of a source. Errors reading it count as not knowing it.
+fn read<H: Hass + Send + Sync + 'static>(
+    source: &Source,
+    hass: &Instances<H>,
+    topics: &TopicStates,
+) -> Box<dyn Future<Item = Option<String>, Error = ()> + Send> {
+    match source {
+        Source::Mqtt(topic) => Box::new(future::ok(topics.get(topic))),
+        Source::Hass { entity, instance } => {
+            match hass.get(instance.as_ref().map(String::as_str)) {
+                Ok(hass) => Box::new(hass.get_state(entity).then(|r| match r {
+                    Ok(state) => Ok(Some(state.name)),
+                    Err(e) => {
+                        println!("failed to read the state for a confirmation: {}", e);
+                        Ok(None)
+                    }
+                })),
+                Err(e) => {
+                    println!("failed to read the state for a confirmation: {}", e);
+                    Box::new(future::ok(None))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::hass::FakeHass;
+
+    #[test]
+    fn confirmed_by_mqtt_state() {
+        let topics = TopicStates::new();
+        topics.update("lounge/amp/state", "1");
+        let hass = Instances::new("default", FakeHass::new());
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let confirmation =
+            Confirmation::mqtt("lounge/amp/state", "0").set_timeout(Duration::from_millis(500));
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let waiting = confirmation.wait(&hass, &topics, &clock);
+        topics.update("lounge/amp/state", "0");
+        assert_eq!(runtime.block_on(waiting).unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn unconfirmed_after_the_timeout() {
+        let topics = TopicStates::new();
+        let hass = Instances::new(
+            "default",
+            FakeHass::new().with_state("switch.printer", "on", None),
+        );
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+
+        let confirmation =
+            Confirmation::hass("switch.printer", "off").set_timeout(Duration::from_millis(20));
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(confirmation.wait(&hass, &topics, &clock))
+            .unwrap();
+        assert_eq!(result, Err("expected off, got on".to_string()));
+
+        let confirmation =
+            Confirmation::mqtt("lounge/amp/state", "0").set_timeout(Duration::from_millis(20));
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(confirmation.wait(&hass, &topics, &clock))
+            .unwrap();
+        assert_eq!(result, Err("expected 0, got nothing".to_string()));
+    }
+
+    #[test]
+    fn value_from_a_json_state() {
+        let confirmation = Confirmation::mqtt("zigbee2mqtt/amp", "OFF").set_pointer("/state");
+        assert_eq!(
+            confirmation.value(r#"{"state":"OFF","linkquality":87}"#),
+            Some("OFF".to_string())
+        );
+        assert_eq!(confirmation.value(r#"{"linkquality":87}"#), None);
+
+        let confirmation =
+            Confirmation::mqtt("shelly/status/switch:0", "false").set_pointer("/output");
+        assert_eq!(
+            confirmation.value(r#"{"id":0,"output":false}"#),
+            Some("false".to_string())
+        );
+    }
+}
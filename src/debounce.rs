@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How long a lock sensor has to keep its state before it counts, and how much flapping is
+/// tolerated before the sensor is reported as faulty.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Debounce {
+    lock_stable: Duration,
+    unlock_stable: Duration,
+    max_changes: usize,
+    window: Duration,
+}
+
+impl Default for Debounce {
+    // Every reading counts right away.
+    fn default() -> Self {
+        Debounce::new(Duration::from_secs(0), Duration::from_secs(0))
+    }
+}
+
+impl Debounce {
+    pub fn new(lock_stable: Duration, unlock_stable: Duration) -> Self {
+        Debounce {
+            lock_stable,
+            unlock_stable,
+            max_changes: 5,
+            window: Duration::from_secs(60),
+        }
+    }
+
+    /// More than `max_changes` changes within `window` are reported as a sensor fault.
+    pub fn set_flapping(mut self, max_changes: usize, window: Duration) -> Self {
+        self.max_changes = max_changes;
+        self.window = window;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    // The sensor settled on a new state.
+    Changed(bool),
+    // Call `settle` again at the given instant.
+    SettleAt(Instant),
+    // The sensor changed `changes` times within the window.
+    Flapping { changes: usize },
+}
+
+/// The debounced state of a single lock sensor. The caller keeps the time, it passes `now` in
+/// and calls `settle` when asked to with `SettleAt`.
+#[derive(Debug)]
+pub struct Debouncer {
+    config: Debounce,
+    // The last reading and since when the sensor reports it.
+    reading: Option<(bool, Instant)>,
+    // The state that was passed on.
+    accepted: Option<bool>,
+    changes: VecDeque<Instant>,
+    flapping: bool,
+}
+
+impl Debouncer {
+    pub fn new(config: Debounce) -> Self {
+        Debouncer {
+            config,
+            reading: None,
+            accepted: None,
+            changes: VecDeque::new(),
+            flapping: false,
+        }
+    }
+
+    pub fn update(&mut self, locked: bool, now: Instant) -> Vec<Output> {
+        while let Some(&at) = self.changes.front() {
+            if now.duration_since(at) <= self.config.window {
+                break;
+            }
+            self.changes.pop_front();
+        }
+        // quiet for a whole window, the next fault is reported again
+        if self.changes.is_empty() {
+            self.flapping = false;
+        }
+
+        match self.reading {
+            // the same state again, e.g. a sensor that reports periodically
+            Some((reading, _)) if reading == locked => {}
+            previous => {
+                self.reading = Some((locked, now));
+                if previous.is_some() {
+                    self.changes.push_back(now);
+                }
+            }
+        }
+
+        let mut outputs = vec![];
+        if self.changes.len() > self.config.max_changes && !self.flapping {
+            self.flapping = true;
+            outputs.push(Output::Flapping {
+                changes: self.changes.len(),
+            });
+        }
+        outputs.extend(self.settle(now));
+        outputs
+    }
+
+    /// Passes the current reading on once it has been stable for long enough.
+    pub fn settle(&mut self, now: Instant) -> Vec<Output> {
+        let (locked, since) = match self.reading {
+            Some(reading) => reading,
+            None => return vec![],
+        };
+        if self.accepted == Some(locked) {
+            return vec![];
+        }
+        let stable = if locked {
+            self.config.lock_stable
+        } else {
+            self.config.unlock_stable
+        };
+        if now >= since + stable {
+            self.accepted = Some(locked);
+            vec![Output::Changed(locked)]
+        } else {
+            vec![Output::SettleAt(since + stable)]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STABLE: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn short_changes_are_ignored() {
+        let t0 = Instant::now();
+        let mut debouncer = Debouncer::new(Debounce::new(STABLE, STABLE));
+
+        assert_eq!(
+            debouncer.update(true, t0),
+            vec![Output::SettleAt(t0 + STABLE)]
+        );
+        let t1 = t0 + Duration::from_secs(1);
+        debouncer.update(false, t1);
+        let t2 = t0 + Duration::from_secs(2);
+        assert_eq!(
+            debouncer.update(true, t2),
+            vec![Output::SettleAt(t2 + STABLE)]
+        );
+
+        // the timer for the first reading comes too early
+        assert_eq!(
+            debouncer.settle(t0 + STABLE),
+            vec![Output::SettleAt(t2 + STABLE)]
+        );
+        assert_eq!(debouncer.settle(t2 + STABLE), vec![Output::Changed(true)]);
+        assert_eq!(debouncer.settle(t2 + STABLE), vec![]);
+    }
+
+    #[test]
+    fn flapping_is_reported_once() {
+        let t0 = Instant::now();
+        let mut debouncer =
+            Debouncer::new(Debounce::default().set_flapping(3, Duration::from_secs(60)));
+        let mut flap = |start: Instant| {
+            (0..8)
+                .flat_map(|i| debouncer.update(i % 2 == 0, start + Duration::from_secs(i)))
+                .filter_map(|o| match o {
+                    Output::Flapping { changes } => Some(changes),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(flap(t0), vec![4]);
+        // still within the window
+        assert!(flap(t0 + Duration::from_secs(30)).is_empty());
+        // quiet for a whole window
+        assert_eq!(flap(t0 + Duration::from_secs(120)), vec![4]);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::auto_shutdown::ShutdownMessage;
+use crate::confirm::Confirmation;
+
+/// The topic and payload conventions of a device firmware.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    // `cmnd/<name>/POWER OFF`, confirmed on `stat/<name>/POWER`.
+    Tasmota,
+    // `shellies/<name>/relay/<channel>/command off`, confirmed on
+    // `shellies/<name>/relay/<channel>`.
+    ShellyGen1,
+    // A `Switch.Set` RPC on `<name>/rpc`, confirmed by `output` on
+    // `<name>/status/switch:<channel>`.
+    ShellyGen2,
+    // `zigbee2mqtt/<name>/set {"state":"OFF"}`, confirmed by `state` on `zigbee2mqtt/<name>`.
+    Zigbee2Mqtt,
+}
+
+/// A switchable device, known by its profile and its name, which is the topic or the device
+/// name configured on it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    profile: Profile,
+    name: String,
+    channel: Option<u8>,
+}
+
+impl Device {
+    pub fn new(profile: Profile, name: &str) -> Self {
+        Device {
+            profile,
+            name: name.to_string(),
+            channel: None,
+        }
+    }
+
+    /// The relay of a device with several of them. Tasmota counts from 1, Shelly from 0, which
+    /// is also the default for Shelly.
+    pub fn set_channel(mut self, channel: u8) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    pub fn command_topic(&self) -> String {
+        match self.profile {
+            Profile::Tasmota => format!("cmnd/{}/{}", self.name, self.power()),
+            Profile::ShellyGen1 => format!(
+                "shellies/{}/relay/{}/command",
+                self.name,
+                self.shelly_channel()
+            ),
+            Profile::ShellyGen2 => format!("{}/rpc", self.name),
+            Profile::Zigbee2Mqtt => format!("zigbee2mqtt/{}/set", self.name),
+        }
+    }
+
+    pub fn state_topic(&self) -> String {
+        match self.profile {
+            Profile::Tasmota => format!("stat/{}/{}", self.name, self.power()),
+            Profile::ShellyGen1 => {
+                format!("shellies/{}/relay/{}", self.name, self.shelly_channel())
+            }
+            Profile::ShellyGen2 => format!("{}/status/switch:{}", self.name, self.shelly_channel()),
+            Profile::Zigbee2Mqtt => format!("zigbee2mqtt/{}", self.name),
+        }
+    }
+
+    pub fn off(&self) -> ShutdownMessage {
+        self.switch(false)
+    }
+
+    pub fn on(&self) -> ShutdownMessage {
+        self.switch(true)
+    }
+
+    /// The message that switches the device, confirmed by the state it reports back.
+    pub fn switch(&self, on: bool) -> ShutdownMessage {
+        let (payload, confirmation) = match self.profile {
+            Profile::Tasmota => {
+                let state = if on { "ON" } else { "OFF" };
+                (
+                    state.to_string(),
+                    Confirmation::mqtt(&self.state_topic(), state),
+                )
+            }
+            Profile::ShellyGen1 => {
+                let state = if on { "on" } else { "off" };
+                (
+                    state.to_string(),
+                    Confirmation::mqtt(&self.state_topic(), state),
+                )
+            }
+            Profile::ShellyGen2 => (
+                serde_json::json!({
+                    "id": 1,
+                    "src": "shutdown",
+                    "method": "Switch.Set",
+                    "params": { "id": self.shelly_channel(), "on": on },
+                })
+                .to_string(),
+                Confirmation::mqtt(&self.state_topic(), &on.to_string()).set_pointer("/output"),
+            ),
+            Profile::Zigbee2Mqtt => {
+                let state = if on { "ON" } else { "OFF" };
+                (
+                    serde_json::json!({ "state": state }).to_string(),
+                    Confirmation::mqtt(&self.state_topic(), state).set_pointer("/state"),
+                )
+            }
+        };
+        ShutdownMessage::new(&self.command_topic(), &payload).confirm(confirmation)
+    }
+
+    fn power(&self) -> String {
+        match self.channel {
+            Some(channel) => format!("POWER{}", channel),
+            None => "POWER".to_string(),
+        }
+    }
+
+    fn shelly_channel(&self) -> u8 {
+        self.channel.unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::confirm::Source;
+
+    fn message(device: &Device) -> (String, String, String) {
+        let message = device.off();
+        let confirmation = message.confirmation().unwrap();
+        let state = match confirmation.source() {
+            Source::Mqtt(topic) => topic.clone(),
+            s => panic!("unexpected source {:?}", s),
+        };
+        (
+            message.topic().to_string(),
+            message.payload().to_string(),
+            state,
+        )
+    }
+
+    #[test]
+    fn topics_and_payloads_of_the_profiles() {
+        assert_eq!(
+            message(&Device::new(Profile::Tasmota, "lounge_amp")),
+            (
+                "cmnd/lounge_amp/POWER".to_string(),
+                "OFF".to_string(),
+                "stat/lounge_amp/POWER".to_string()
+            )
+        );
+        assert_eq!(
+            message(&Device::new(Profile::Tasmota, "kitchen").set_channel(2)).0,
+            "cmnd/kitchen/POWER2"
+        );
+        assert_eq!(
+            message(&Device::new(Profile::ShellyGen1, "shelly1-printer")),
+            (
+                "shellies/shelly1-printer/relay/0/command".to_string(),
+                "off".to_string(),
+                "shellies/shelly1-printer/relay/0".to_string()
+            )
+        );
+        assert_eq!(
+            message(&Device::new(Profile::ShellyGen2, "shellyplus2pm-beamer").set_channel(1)),
+            (
+                "shellyplus2pm-beamer/rpc".to_string(),
+                r#"{"id":1,"method":"Switch.Set","params":{"id":1,"on":false},"src":"shutdown"}"#
+                    .to_string(),
+                "shellyplus2pm-beamer/status/switch:1".to_string()
+            )
+        );
+        assert_eq!(
+            message(&Device::new(Profile::Zigbee2Mqtt, "lounge_leds")),
+            (
+                "zigbee2mqtt/lounge_leds/set".to_string(),
+                r#"{"state":"OFF"}"#.to_string(),
+                "zigbee2mqtt/lounge_leds".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn confirmed_by_the_reported_state() {
+        let message = Device::new(Profile::ShellyGen2, "shellyplus1-amp").off();
+        let confirmation = message.confirmation().unwrap();
+        assert_eq!(
+            confirmation.value(r#"{"id":0,"source":"MQTT","output":false}"#),
+            Some(confirmation.expected().to_string())
+        );
+
+        let message = Device::new(Profile::Zigbee2Mqtt, "lounge_leds").on();
+        let confirmation = message.confirmation().unwrap();
+        assert_eq!(
+            confirmation.value(r#"{"state":"ON","brightness":254}"#),
+            Some(confirmation.expected().to_string())
+        );
+    }
+}
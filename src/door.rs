@@ -0,0 +1,303 @@
+use crate::debounce::{self, Debounce, Debouncer};
+use std::time::Instant;
+
+/// How the lock states of several doors make up the state of the space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Combine {
+    // The space is closed once every door is locked.
+    All,
+    // Locking any of the doors closes the space.
+    Any,
+}
+
+/// A lock sensor that publishes on `topic`. By default `1` means locked and anything else
+/// unlocked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Door {
+    name: String,
+    topic: String,
+    // The entity that knows the state of the lock and the state it has while it is locked.
+    entity: Option<(String, String)>,
+    // Where the value is found in a JSON payload.
+    pointer: Option<String>,
+    locked: Vec<String>,
+    // Empty means anything that is not `locked`.
+    unlocked: Vec<String>,
+}
+
+impl Door {
+    pub fn new(name: &str, topic: &str) -> Self {
+        Door {
+            name: name.to_string(),
+            topic: topic.to_string(),
+            entity: None,
+            pointer: None,
+            locked: vec!["1".to_string()],
+            unlocked: vec![],
+        }
+    }
+
+    /// Takes the value from a JSON payload at the given JSON pointer, e.g. `/lock_state` for
+    /// `{"lock_state":"locked"}` from Zigbee2MQTT.
+    pub fn set_pointer(mut self, pointer: &str) -> Self {
+        self.pointer = Some(pointer.to_string());
+        self
+    }
+
+    /// The values that mean locked and unlocked, e.g. `ON` and `OFF` from Tasmota. Other values
+    /// are ignored, unless `unlocked` is empty.
+    pub fn set_values(mut self, locked: &[&str], unlocked: &[&str]) -> Self {
+        self.locked = locked.iter().map(|v| v.to_string()).collect();
+        self.unlocked = unlocked.iter().map(|v| v.to_string()).collect();
+        self
+    }
+
+    /// Reads the lock state from Home Assistant on startup and after reconnecting to the
+    /// broker. Retained messages on the topic are ignored then, they may be outdated.
+    pub fn set_entity(mut self, entity: &str, locked: &str) -> Self {
+        self.entity = Some((entity.to_string(), locked.to_string()));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn entity(&self) -> Option<(&str, &str)> {
+        self.entity
+            .as_ref()
+            .map(|(entity, locked)| (entity.as_str(), locked.as_str()))
+    }
+
+    /// Whether `payload` says the door is locked.
+    pub fn parse(&self, payload: &str) -> Result<bool, String> {
+        let value = match &self.pointer {
+            Some(pointer) => {
+                let json: serde_json::Value =
+                    serde_json::from_str(payload).map_err(|e| e.to_string())?;
+                match json.pointer(pointer) {
+                    Some(serde_json::Value::String(value)) => value.clone(),
+                    Some(value) => value.to_string(),
+                    None => return Err(format!("nothing at {}", pointer)),
+                }
+            }
+            None => payload.to_string(),
+        };
+
+        if self.locked.contains(&value) {
+            Ok(true)
+        } else if self.unlocked.is_empty() || self.unlocked.contains(&value) {
+            Ok(false)
+        } else {
+            Err(format!("unknown value {}", value))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    // The combined state of all doors changed.
+    Locked(bool),
+    // The debounced state of a single door changed.
+    DoorChanged,
+    // Call `settle` again at the given instant.
+    SettleAt(Instant),
+    // The sensor of a door changed `changes` times within the flapping window.
+    Flapping { door: String, changes: usize },
+}
+
+/// The debounced lock states of all doors and what they add up to with `Combine`.
+#[derive(Debug)]
+pub struct Doors {
+    doors: Vec<(Door, Debouncer, Option<bool>)>,
+    combine: Combine,
+    // The combined state that was passed on last.
+    locked: Option<bool>,
+}
+
+impl Doors {
+    pub fn new(doors: &[Door], combine: Combine, debounce: Debounce) -> Self {
+        Doors {
+            doors: doors
+                .iter()
+                .map(|door| (door.clone(), Debouncer::new(debounce), None))
+                .collect(),
+            combine,
+            locked: None,
+        }
+    }
+
+    /// The debounced state of every door, `None` until it is known.
+    pub fn states(&self) -> impl Iterator<Item = (&Door, Option<bool>)> {
+        self.doors.iter().map(|(door, _, locked)| (door, *locked))
+    }
+
+    /// Takes a message on `topic`, which may be the topic of none, one or several doors.
+    /// Payloads a door does not understand are ignored.
+    pub fn update(&mut self, topic: &str, payload: &str, now: Instant) -> Vec<Output> {
+        let mut outputs = vec![];
+        for (door, debouncer, state) in self.doors.iter_mut() {
+            if door.topic != topic {
+                continue;
+            }
+            match door.parse(payload) {
+                Ok(locked) => {
+                    outputs.extend(Self::debounced(door, state, debouncer.update(locked, now)))
+                }
+                Err(e) => println!("ignoring {} from {}: {}", payload, door.name, e),
+            }
+        }
+        self.combined(outputs)
+    }
+
+    /// Takes the state of a door that is known from somewhere else than its topic.
+    pub fn set(&mut self, name: &str, locked: bool, now: Instant) -> Vec<Output> {
+        let mut outputs = vec![];
+        for (door, debouncer, state) in self.doors.iter_mut() {
+            if door.name == name {
+                outputs.extend(Self::debounced(door, state, debouncer.update(locked, now)));
+            }
+        }
+        self.combined(outputs)
+    }
+
+    pub fn settle(&mut self, now: Instant) -> Vec<Output> {
+        let mut outputs = vec![];
+        for (door, debouncer, state) in self.doors.iter_mut() {
+            outputs.extend(Self::debounced(door, state, debouncer.settle(now)));
+        }
+        self.combined(outputs)
+    }
+
+    fn debounced(
+        door: &Door,
+        state: &mut Option<bool>,
+        outputs: Vec<debounce::Output>,
+    ) -> Vec<Output> {
+        outputs
+            .into_iter()
+            .map(|output| match output {
+                debounce::Output::Changed(locked) => {
+                    *state = Some(locked);
+                    Output::DoorChanged
+                }
+                debounce::Output::SettleAt(at) => Output::SettleAt(at),
+                debounce::Output::Flapping { changes } => Output::Flapping {
+                    door: door.name.clone(),
+                    changes,
+                },
+            })
+            .collect()
+    }
+
+    // Adds the combined state if it changed. A single door can decide it, e.g. an open one
+    // with `All`, otherwise it takes all doors to agree. With `Any` a door that has not reported
+    // yet counts as unlocked, as it does not keep the zone locked; with `All` it could still be
+    // the one that is open.
+    fn combined(&mut self, mut outputs: Vec<Output>) -> Vec<Output> {
+        let states = self
+            .doors
+            .iter()
+            .map(|(_, _, locked)| *locked)
+            .collect::<Vec<_>>();
+        let decisive = self.combine == Combine::Any;
+        let locked = if states.contains(&Some(decisive)) {
+            Some(decisive)
+        } else if states
+            .iter()
+            .all(|s| *s == Some(!decisive) || (decisive && s.is_none()))
+            && states.iter().any(Option::is_some)
+        {
+            Some(!decisive)
+        } else {
+            None
+        };
+        if locked.is_some() && locked != self.locked {
+            self.locked = locked;
+            outputs.extend(locked.map(Output::Locked));
+        }
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doors(combine: Combine) -> Doors {
+        Doors::new(
+            &[
+                Door::new("front", "door/front"),
+                Door::new("back", "door/back"),
+            ],
+            combine,
+            Debounce::default(),
+        )
+    }
+
+    fn locked(outputs: Vec<Output>) -> Option<bool> {
+        outputs.into_iter().find_map(|o| match o {
+            Output::Locked(locked) => Some(locked),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn all_doors_have_to_be_locked() {
+        let now = Instant::now();
+        let mut doors = doors(Combine::All);
+        assert_eq!(locked(doors.update("door/front", "1", now)), None);
+        assert_eq!(locked(doors.update("door/back", "1", now)), Some(true));
+        assert_eq!(locked(doors.update("door/back", "0", now)), Some(false));
+        assert_eq!(
+            doors
+                .states()
+                .map(|(door, locked)| (door.name(), locked))
+                .collect::<Vec<_>>(),
+            vec![("front", Some(true)), ("back", Some(false))]
+        );
+    }
+
+    #[test]
+    fn any_locked_door_is_enough() {
+        let now = Instant::now();
+        let mut doors = doors(Combine::Any);
+        // the back door has not reported yet, it does not keep the zone locked either
+        assert_eq!(locked(doors.update("door/front", "0", now)), Some(false));
+        assert_eq!(locked(doors.update("door/back", "1", now)), Some(true));
+        assert_eq!(locked(doors.update("door/back", "0", now)), Some(false));
+        assert_eq!(locked(doors.update("door/other", "1", now)), None);
+
+        // the front door has not reported yet
+        doors = self::doors(Combine::Any);
+        assert_eq!(locked(doors.update("door/back", "1", now)), Some(true));
+        assert_eq!(locked(doors.update("door/back", "0", now)), Some(false));
+    }
+
+    #[test]
+    fn payloads_of_off_the_shelf_devices() {
+        let zigbee = Door::new("front", "zigbee2mqtt/front_lock")
+            .set_pointer("/lock_state")
+            .set_values(&["locked"], &["unlocked"]);
+        assert_eq!(
+            zigbee.parse(r#"{"lock_state":"locked","battery":80}"#),
+            Ok(true)
+        );
+        assert_eq!(zigbee.parse(r#"{"lock_state":"unlocked"}"#), Ok(false));
+        assert!(zigbee.parse(r#"{"lock_state":"jammed"}"#).is_err());
+        assert!(zigbee.parse(r#"{"battery":80}"#).is_err());
+        assert!(zigbee.parse("locked").is_err());
+
+        let tasmota = Door::new("back", "stat/back_lock/POWER").set_values(&["ON"], &["OFF"]);
+        assert_eq!(tasmota.parse("ON"), Ok(true));
+        assert_eq!(tasmota.parse("OFF"), Ok(false));
+
+        let contact = Door::new("side", "side/contact").set_pointer("/contact");
+        assert_eq!(contact.parse(r#"{"contact":1}"#), Ok(true));
+        assert_eq!(contact.parse(r#"{"contact":0}"#), Ok(false));
+    }
+}
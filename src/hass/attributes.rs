@@ -1,7 +1,7 @@
 use serde_json::Value;
 use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Attributes(HashMap<String, Value>);
 
 impl Attributes {
@@ -13,6 +13,14 @@ impl Attributes {
         self.0.insert(key.as_ref().to_string(), value.into());
         self
     }
+
+    pub fn get(&self, key: impl AsRef<str>) -> Option<&Value> {
+        self.0.get(key.as_ref())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
 }
 
 #[cfg(test)]
@@ -22,4 +30,11 @@ mod tests {
     fn test_attribute() {
         Attributes::new().set("foo", 1).set("baz", "foo");
     }
+
+    #[test]
+    fn test_attribute_keys() {
+        let attrs = Attributes::new().set("foo", 1);
+        assert_eq!(attrs.keys().collect::<Vec<_>>(), vec!["foo"]);
+        assert_eq!(attrs.get("foo"), Some(&Value::from(1)));
+    }
 }
@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+
+/// The subset of `/api/config` we care about.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    pub location_name: String,
+    pub version: String,
+    pub time_zone: String,
+    #[serde(default)]
+    pub latitude: f64,
+    #[serde(default)]
+    pub longitude: f64,
+    #[serde(default)]
+    pub unit_system: HashMap<String, String>,
+    #[serde(default)]
+    pub components: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_config() {
+        let config: Config = serde_json::from_str(
+            r#"{"location_name": "w17", "version": "0.100.0", "time_zone": "Europe/Berlin",
+                "latitude": 49.87, "longitude": 8.65, "elevation": 144,
+                "unit_system": {"temperature": "°C"}, "components": ["climate", "mqtt"]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.location_name, "w17");
+        assert!(config.components.contains(&"climate".to_string()));
+    }
+}
@@ -0,0 +1,226 @@
+use super::{Attributes, Config, Domain, Error, Hass, Request, ServiceCall, State};
+use chrono::{DateTime, Utc};
+use futures::Future;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Inner {
+    states: HashMap<String, State>,
+    services: Vec<Domain>,
+    config: Config,
+    templates: HashMap<String, String>,
+    history: HashMap<String, Vec<State>>,
+    calls: Vec<ServiceCall>,
+    events: Vec<(String, Option<Attributes>)>,
+    unreachable: bool,
+}
+
+/// In-memory stand-in for a Home Assistant instance. It serves the states and services it was
+/// set up with and records every service call and event so tests can inspect them afterwards.
+#[derive(Clone, Default)]
+pub struct FakeHass {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl FakeHass {
+    pub fn new() -> Self {
+        FakeHass::default()
+    }
+
+    pub fn with_state(
+        self,
+        entity: impl AsRef<str>,
+        state: impl AsRef<str>,
+        attributes: Option<Attributes>,
+    ) -> Self {
+        let mut state = State::new(state, attributes);
+        state.entity_id = Some(entity.as_ref().to_string());
+        self.lock()
+            .states
+            .insert(entity.as_ref().to_string(), state);
+        self
+    }
+
+    pub fn with_services(self, domains: Vec<Domain>) -> Self {
+        self.lock().services = domains;
+        self
+    }
+
+    /// The service catalogue can not be fetched, as if the instance was down.
+    pub fn unreachable(self) -> Self {
+        self.lock().unreachable = true;
+        self
+    }
+
+    pub fn with_config(self, config: Config) -> Self {
+        self.lock().config = config;
+        self
+    }
+
+    /// Unknown templates render to an empty string.
+    pub fn with_template(self, template: impl AsRef<str>, rendered: impl AsRef<str>) -> Self {
+        self.lock()
+            .templates
+            .insert(template.as_ref().to_string(), rendered.as_ref().to_string());
+        self
+    }
+
+    /// The history is served as is, whatever period is asked for.
+    pub fn with_history(self, entity: impl AsRef<str>, states: Vec<State>) -> Self {
+        self.lock()
+            .history
+            .insert(entity.as_ref().to_string(), states);
+        self
+    }
+
+    /// All service calls made so far, in order.
+    pub fn calls(&self) -> Vec<ServiceCall> {
+        self.lock().calls.clone()
+    }
+
+    /// All events fired so far, in order.
+    pub fn events(&self) -> Vec<(String, Option<Attributes>)> {
+        self.lock().events.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Mutex poisoned")
+    }
+}
+
+impl Hass for FakeHass {
+    fn get_state(
+        &self,
+        name: impl AsRef<str>,
+    ) -> Box<dyn Future<Item = State, Error = Error> + Send> {
+        let result = self
+            .lock()
+            .states
+            .get(name.as_ref())
+            .cloned()
+            .ok_or_else(|| Error::UnknownEntity(name.as_ref().to_string()));
+        Box::new(futures::future::result(result))
+    }
+
+    fn get_states(&self) -> Box<dyn Future<Item = Vec<State>, Error = Error> + Send> {
+        let states = self.lock().states.values().cloned().collect();
+        Box::new(futures::future::ok(states))
+    }
+
+    fn set_state(
+        &self,
+        name: impl AsRef<str>,
+        attributes: Attributes,
+    ) -> Box<dyn Future<Item = State, Error = Error> + Send> {
+        let mut inner = self.lock();
+        let state = inner
+            .states
+            .entry(name.as_ref().to_string())
+            .or_insert_with(|| {
+                let mut state = State::new("unknown", None);
+                state.entity_id = Some(name.as_ref().to_string());
+                state
+            });
+        state.attributes = Some(attributes);
+        Box::new(futures::future::ok(state.clone()))
+    }
+
+    fn get_services(&self) -> Box<dyn Future<Item = Vec<Domain>, Error = Error> + Send> {
+        let inner = self.lock();
+        if inner.unreachable {
+            return Box::new(futures::future::err(Error::HomeAssistant {
+                request: Request::GetServices,
+                error: super::home_assistant::Error::CircuitOpen,
+            }));
+        }
+        Box::new(futures::future::ok(inner.services.clone()))
+    }
+
+    fn call_service(
+        &self,
+        domain: impl AsRef<str>,
+        name: impl AsRef<str>,
+        attributes: Option<Attributes>,
+    ) -> Box<dyn Future<Item = Vec<State>, Error = Error> + Send> {
+        // Like a real request the call only happens once the future is polled.
+        let inner = Arc::clone(&self.inner);
+        let call = ServiceCall::new(domain, name, attributes);
+        Box::new(futures::future::lazy(move || {
+            inner.lock().expect("Mutex poisoned").calls.push(call);
+            Ok(vec![])
+        }))
+    }
+
+    fn get_config(&self) -> Box<dyn Future<Item = Config, Error = Error> + Send> {
+        Box::new(futures::future::ok(self.lock().config.clone()))
+    }
+
+    fn fire_event(
+        &self,
+        event_type: impl AsRef<str>,
+        data: Option<Attributes>,
+    ) -> Box<dyn Future<Item = String, Error = Error> + Send> {
+        let inner = Arc::clone(&self.inner);
+        let event_type = event_type.as_ref().to_string();
+        Box::new(futures::future::lazy(move || {
+            let message = format!("Event {} fired.", event_type);
+            inner
+                .lock()
+                .expect("Mutex poisoned")
+                .events
+                .push((event_type, data));
+            Ok(message)
+        }))
+    }
+
+    fn render_template(
+        &self,
+        template: impl AsRef<str>,
+    ) -> Box<dyn Future<Item = String, Error = Error> + Send> {
+        let rendered = self
+            .lock()
+            .templates
+            .get(template.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        Box::new(futures::future::ok(rendered))
+    }
+
+    fn get_history(
+        &self,
+        entity: impl AsRef<str>,
+        _start: DateTime<Utc>,
+        _end: DateTime<Utc>,
+    ) -> Box<dyn Future<Item = Vec<State>, Error = Error> + Send> {
+        let history = self
+            .lock()
+            .history
+            .get(entity.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        Box::new(futures::future::ok(history))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_service_calls() {
+        let hass = FakeHass::new();
+        hass.call_service("light", "turn_off", None).wait().unwrap();
+        let calls = hass.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].to_string(), "light.turn_off");
+    }
+
+    #[test]
+    fn serves_configured_states() {
+        let hass = FakeHass::new().with_state("sensor.printer_state", "printing", None);
+        let state = hass.get_state("sensor.printer_state").wait().unwrap();
+        assert_eq!(state.name, "printing");
+        assert!(hass.get_state("sensor.unknown").wait().is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use super::{Error, Hass, ServiceCall, State};
+use futures::Future;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Named Home Assistant instances, e.g. one per floor. Actions that do not name an instance
+/// are sent to the default one.
+pub struct Instances<H> {
+    default: String,
+    instances: HashMap<String, Arc<H>>,
+}
+
+// derive(Clone) would require `H: Clone`
+impl<H> Clone for Instances<H> {
+    fn clone(&self) -> Self {
+        Instances {
+            default: self.default.clone(),
+            instances: self.instances.clone(),
+        }
+    }
+}
+
+impl<H> Instances<H> {
+    pub fn new(default: impl AsRef<str>, hass: H) -> Self {
+        let mut instances = HashMap::new();
+        instances.insert(default.as_ref().to_string(), Arc::new(hass));
+        Instances {
+            default: default.as_ref().to_string(),
+            instances,
+        }
+    }
+
+    pub fn add(mut self, name: impl AsRef<str>, hass: H) -> Self {
+        self.instances
+            .insert(name.as_ref().to_string(), Arc::new(hass));
+        self
+    }
+
+    /// Looks up an instance by name, `None` is the default instance.
+    pub fn get(&self, name: Option<&str>) -> Result<&H, Error> {
+        let name = name.unwrap_or(&self.default);
+        self.instances
+            .get(name)
+            .map(|h| &**h)
+            .ok_or_else(|| Error::UnknownInstance(name.to_string()))
+    }
+
+    pub fn default(&self) -> &H {
+        &self.instances[&self.default]
+    }
+}
+
+impl<H: Hass> Instances<H> {
+    /// Makes the service call on the named instance, `None` is the default instance.
+    pub fn call(
+        &self,
+        instance: Option<&str>,
+        call: &ServiceCall,
+    ) -> Box<dyn Future<Item = Vec<State>, Error = Error> + Send> {
+        match self.get(instance) {
+            Ok(hass) => call.call(hass),
+            Err(e) => Box::new(futures::future::err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_by_name() {
+        let instances = Instances::new("ground_floor", 0).add("first_floor", 1);
+        assert_eq!(*instances.get(None).unwrap(), 0);
+        assert_eq!(*instances.get(Some("first_floor")).unwrap(), 1);
+        assert!(instances.get(Some("basement")).is_err());
+        assert_eq!(*instances.default(), 0);
+    }
+
+    #[test]
+    fn call_on_named_instance() {
+        let ground_floor = crate::hass::FakeHass::new();
+        let first_floor = crate::hass::FakeHass::new();
+        let instances = Instances::new("ground_floor", ground_floor.clone())
+            .add("first_floor", first_floor.clone());
+        let call = ServiceCall::new("light", "turn_off", None);
+
+        instances.call(Some("first_floor"), &call).wait().unwrap();
+        assert!(instances.call(Some("basement"), &call).wait().is_err());
+
+        assert!(ground_floor.calls().is_empty());
+        assert_eq!(first_floor.calls().len(), 1);
+    }
+}
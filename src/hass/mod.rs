@@ -1,21 +1,82 @@
+use chrono::{DateTime, Utc};
 use futures::Future;
 
 mod attributes;
+mod config;
+#[cfg(test)]
+mod fake;
 mod home_assistant;
+mod instances;
+mod retry;
+mod service;
 mod state;
 
 pub use attributes::Attributes;
+pub use config::Config;
+#[cfg(test)]
+pub use fake::FakeHass;
 pub use home_assistant::{HomeAssistant, HomeAssistantConfiguration};
+pub use instances::Instances;
+pub use retry::RetryPolicy;
+pub use service::{Domain, ServiceCall, ServiceUse, Services};
 pub use state::State;
 
+/// The request that was being made when an error occurred.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    GetState(String),
+    GetStates,
+    SetState(String),
+    GetServices,
+    CallService {
+        service: String,
+        entity: Option<String>,
+    },
+    GetConfig,
+    FireEvent(String),
+    RenderTemplate,
+    GetHistory(String),
+}
+
+impl std::fmt::Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Request::GetState(entity) => write!(f, "getting the state of {}", entity),
+            Request::GetStates => write!(f, "getting all states"),
+            Request::SetState(entity) => write!(f, "setting the state of {}", entity),
+            Request::GetServices => write!(f, "getting the service catalogue"),
+            Request::CallService {
+                service,
+                entity: Some(entity),
+            } => write!(f, "calling {} for {}", service, entity),
+            Request::CallService { service, .. } => write!(f, "calling {}", service),
+            Request::GetConfig => write!(f, "getting the configuration"),
+            Request::FireEvent(event_type) => write!(f, "firing event {}", event_type),
+            Request::RenderTemplate => write!(f, "rendering a template"),
+            Request::GetHistory(entity) => write!(f, "getting the history of {}", entity),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
-    HomeAssistant(home_assistant::Error),
+    HomeAssistant {
+        request: Request,
+        error: home_assistant::Error,
+    },
+    UnknownEntity(String),
+    UnknownInstance(String),
 }
 
-impl From<home_assistant::Error> for Error {
-    fn from(e: home_assistant::Error) -> Error {
-        Error::HomeAssistant(e)
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::HomeAssistant { request, error } => write!(f, "{} failed: {}", request, error),
+            Error::UnknownEntity(entity) => write!(f, "unknown entity {}", entity),
+            Error::UnknownInstance(name) => {
+                write!(f, "unknown Home Assistant instance {}", name)
+            }
+        }
     }
 }
 
@@ -24,25 +85,41 @@ pub trait Hass {
         &self,
         name: impl AsRef<str>,
     ) -> Box<dyn Future<Item = State, Error = Error> + Send>;
+    fn get_states(&self) -> Box<dyn Future<Item = Vec<State>, Error = Error> + Send>;
     fn set_state(
         &self,
         name: impl AsRef<str>,
         attributes: Attributes,
     ) -> Box<dyn Future<Item = State, Error = Error> + Send>;
+    fn get_services(&self) -> Box<dyn Future<Item = Vec<Domain>, Error = Error> + Send>;
     fn call_service(
         &self,
         domain: impl AsRef<str>,
         name: impl AsRef<str>,
         attributes: Option<Attributes>,
     ) -> Box<dyn Future<Item = Vec<State>, Error = Error> + Send>;
+    fn get_config(&self) -> Box<dyn Future<Item = Config, Error = Error> + Send>;
+    fn fire_event(
+        &self,
+        event_type: impl AsRef<str>,
+        data: Option<Attributes>,
+    ) -> Box<dyn Future<Item = String, Error = Error> + Send>;
+    fn render_template(
+        &self,
+        template: impl AsRef<str>,
+    ) -> Box<dyn Future<Item = String, Error = Error> + Send>;
+    /// All states `entity` had between `start` and `end`, oldest first. The first state is the
+    /// one the entity had at `start`.
+    fn get_history(
+        &self,
+        entity: impl AsRef<str>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Box<dyn Future<Item = Vec<State>, Error = Error> + Send>;
 }
 
-pub fn set_temperature(
-    hass: &impl Hass,
-    entity: impl AsRef<str>,
-    temperature: f32,
-) -> impl Future<Item = Vec<State>, Error = Error> {
-    hass.call_service(
+pub fn set_temperature_call(entity: impl AsRef<str>, temperature: f32) -> ServiceCall {
+    ServiceCall::new(
         "climate",
         "set_temperature",
         Some(
@@ -51,8 +128,4 @@ pub fn set_temperature(
                 .set("temperature", temperature),
         ),
     )
-    .map(|r| {
-        println!("response: {:?}", r);
-        r
-    })
 }
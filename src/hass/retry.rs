@@ -0,0 +1,194 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often and how fast a failed idempotent request is retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        RetryPolicy::default()
+    }
+
+    /// A policy that gives up after the first attempt.
+    pub fn none() -> Self {
+        RetryPolicy::default().set_max_attempts(1)
+    }
+
+    pub fn set_max_attempts(mut self, value: u32) -> Self {
+        self.max_attempts = value.max(1);
+        self
+    }
+
+    pub fn set_initial_backoff(mut self, value: Duration) -> Self {
+        self.initial_backoff = value;
+        self
+    }
+
+    pub fn set_max_backoff(mut self, value: Duration) -> Self {
+        self.max_backoff = value;
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Time to wait after the given (1-based) failed attempt. The backoff doubles with every
+    /// attempt until it reaches `max_backoff`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // A trial request is out. Should it never report back, e.g. because its future was
+    // dropped, another one is let through at `until`.
+    HalfOpen { until: Instant },
+}
+
+/// Stops sending requests to an instance that keeps failing. After `threshold` consecutive
+/// failures the breaker opens and all calls fail right away. Once `reset_after` has passed a
+/// single trial request is let through; its outcome closes or re-opens the breaker. A trial
+/// that does not report back within `reset_after` is replaced by the next caller.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    reset_after: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, reset_after: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            reset_after,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    pub fn allow(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } | BreakerState::HalfOpen { until } if now >= until => {
+                *state = BreakerState::HalfOpen {
+                    until: now + self.reset_after,
+                };
+                true
+            }
+            BreakerState::Open { .. } => false,
+            // only the first caller after the reset gets to try
+            BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().expect("Mutex poisoned") = BreakerState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self, now: Instant) {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            _ => self.threshold,
+        };
+        *state = if failures >= self.threshold {
+            println!(
+                "home assistant circuit breaker opened after {} failures",
+                failures
+            );
+            BreakerState::Open {
+                until: now + self.reset_after,
+            }
+        } else {
+            BreakerState::Closed { failures }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let policy = RetryPolicy::new()
+            .set_initial_backoff(Duration::from_secs(1))
+            .set_max_backoff(Duration::from_secs(5));
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(4), Duration::from_secs(5));
+        assert_eq!(policy.backoff(100), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn breaker_opens_and_recovers() {
+        let now = Instant::now();
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+
+        breaker.record_failure(now);
+        assert!(breaker.allow(now));
+        breaker.record_failure(now);
+        assert!(!breaker.allow(now));
+        assert!(!breaker.allow(now + Duration::from_secs(29)));
+
+        // a single trial request after the reset period
+        assert!(breaker.allow(now + Duration::from_secs(30)));
+        assert!(!breaker.allow(now + Duration::from_secs(30)));
+
+        breaker.record_success();
+        assert!(breaker.allow(now + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn failed_trial_reopens_the_breaker() {
+        let now = Instant::now();
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        for _ in 0..3 {
+            breaker.record_failure(now);
+        }
+        let later = now + Duration::from_secs(30);
+        assert!(breaker.allow(later));
+        breaker.record_failure(later);
+        assert!(!breaker.allow(later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn dropped_trial_does_not_keep_the_breaker_open() {
+        let now = Instant::now();
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        breaker.record_failure(now);
+
+        // the trial never records its result
+        let later = now + Duration::from_secs(30);
+        assert!(breaker.allow(later));
+        assert!(!breaker.allow(later + Duration::from_secs(29)));
+        assert!(breaker.allow(later + Duration::from_secs(30)));
+
+        breaker.record_success();
+        assert!(breaker.allow(later + Duration::from_secs(31)));
+    }
+}
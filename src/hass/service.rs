@@ -0,0 +1,214 @@
+use super::{Attributes, Error, Hass, State};
+use futures::Future;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Keys that address the entities a service acts on. They are accepted by every service even
+// though newer Home Assistant versions describe them as `target` rather than as fields.
+const TARGET_FIELDS: &[&str] = &["entity_id", "device_id", "area_id"];
+
+/// A single service as described by the `/api/services` catalogue.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Service {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub fields: HashMap<String, Value>,
+}
+
+/// All services of one domain as returned by `/api/services`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Domain {
+    pub domain: String,
+    pub services: HashMap<String, Service>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ServiceError {
+    UnknownDomain(String),
+    UnknownService(String, String),
+    UnknownField {
+        domain: String,
+        service: String,
+        field: String,
+    },
+}
+
+impl std::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServiceError::UnknownDomain(domain) => write!(f, "unknown domain {}", domain),
+            ServiceError::UnknownService(domain, service) => {
+                write!(f, "unknown service {}.{}", domain, service)
+            }
+            ServiceError::UnknownField {
+                domain,
+                service,
+                field,
+            } => write!(f, "service {}.{} has no field {}", domain, service, field),
+        }
+    }
+}
+
+/// The service catalogue of a Home Assistant instance, used to check service calls before they
+/// are made.
+pub struct Services(HashMap<String, HashMap<String, Service>>);
+
+impl Services {
+    pub fn new(domains: Vec<Domain>) -> Self {
+        Services(
+            domains
+                .into_iter()
+                .map(|d| (d.domain, d.services))
+                .collect(),
+        )
+    }
+
+    pub fn check(&self, call: &ServiceUse) -> Result<(), ServiceError> {
+        let services = self
+            .0
+            .get(&call.domain)
+            .ok_or_else(|| ServiceError::UnknownDomain(call.domain.clone()))?;
+        let service = services.get(&call.service).ok_or_else(|| {
+            ServiceError::UnknownService(call.domain.clone(), call.service.clone())
+        })?;
+
+        for field in call.fields.iter() {
+            if !TARGET_FIELDS.contains(&field.as_str()) && !service.fields.contains_key(field) {
+                return Err(ServiceError::UnknownField {
+                    domain: call.domain.clone(),
+                    service: call.service.clone(),
+                    field: field.to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A service an action calls and the data fields it sends. The values may only be known when
+/// the call is made, e.g. a captured setpoint, the catalogue does not need them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceUse {
+    pub domain: String,
+    pub service: String,
+    pub fields: Vec<String>,
+}
+
+impl ServiceUse {
+    pub fn new(domain: impl AsRef<str>, service: impl AsRef<str>, fields: &[&str]) -> Self {
+        ServiceUse {
+            domain: domain.as_ref().to_string(),
+            service: service.as_ref().to_string(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+impl<'a> From<&'a ServiceCall> for ServiceUse {
+    fn from(call: &'a ServiceCall) -> Self {
+        ServiceUse {
+            domain: call.domain.clone(),
+            service: call.service.clone(),
+            fields: call
+                .data
+                .iter()
+                .flat_map(|data| data.keys().map(String::from))
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for ServiceUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}", self.domain, self.service)
+    }
+}
+
+/// A service call that is known ahead of time, e.g. one of the configured shutdown actions.
+#[derive(Debug, Clone)]
+pub struct ServiceCall {
+    pub domain: String,
+    pub service: String,
+    pub data: Option<Attributes>,
+}
+
+impl ServiceCall {
+    pub fn new(
+        domain: impl AsRef<str>,
+        service: impl AsRef<str>,
+        data: Option<Attributes>,
+    ) -> Self {
+        ServiceCall {
+            domain: domain.as_ref().to_string(),
+            service: service.as_ref().to_string(),
+            data,
+        }
+    }
+
+    pub fn call(
+        &self,
+        hass: &impl Hass,
+    ) -> Box<dyn Future<Item = Vec<State>, Error = Error> + Send> {
+        hass.call_service(&self.domain, &self.service, self.data.clone())
+    }
+}
+
+impl std::fmt::Display for ServiceCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}", self.domain, self.service)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn services() -> Services {
+        let domains: Vec<Domain> = serde_json::from_str(
+            r#"[{"domain": "climate", "services": {"set_temperature": {"fields": {"temperature": {}}}}}]"#,
+        )
+        .unwrap();
+        Services::new(domains)
+    }
+
+    #[test]
+    fn check_known_service() {
+        let call = ServiceCall::new(
+            "climate",
+            "set_temperature",
+            Some(
+                Attributes::new()
+                    .set("entity_id", "climate.lounge")
+                    .set("temperature", 18.0),
+            ),
+        );
+        assert_eq!(services().check(&ServiceUse::from(&call)), Ok(()));
+    }
+
+    #[test]
+    fn check_unknown_service() {
+        let call = ServiceUse::new("climate", "set_hvac_mode", &[]);
+        assert_eq!(
+            services().check(&call),
+            Err(ServiceError::UnknownService(
+                "climate".to_string(),
+                "set_hvac_mode".to_string()
+            ))
+        );
+        let call = ServiceUse::new("light", "turn_off", &[]);
+        assert_eq!(
+            services().check(&call),
+            Err(ServiceError::UnknownDomain("light".to_string()))
+        );
+    }
+
+    #[test]
+    fn check_unknown_field() {
+        let call = ServiceUse::new("climate", "set_temperature", &["temprature"]);
+        assert!(services().check(&call).is_err());
+    }
+}
@@ -1,18 +1,26 @@
 use super::Attributes;
+use chrono::{DateTime, Utc};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct State {
+    // entity the state belongs to, only present in responses from Home Assistant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entity_id: Option<String>,
     // name of the state
     #[serde(rename = "state")]
     pub name: String,
     pub attributes: Option<Attributes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_changed: Option<DateTime<Utc>>,
 }
 
 impl State {
     pub fn new(name: impl AsRef<str>, attributes: Option<Attributes>) -> Self {
         State {
+            entity_id: None,
             name: name.as_ref().to_string(),
             attributes: attributes,
+            last_changed: None,
         }
     }
 }
@@ -30,4 +38,19 @@ mod tests {
 
         State::new("some_state", Some(attrs));
     }
+
+    #[test]
+    fn decode_state_list() {
+        let states: Vec<State> = serde_json::from_str(
+            r#"[{"entity_id": "sun.sun", "state": "below_horizon", "attributes": {"elevation": -10},
+                 "last_changed": "2019-10-18T20:00:00.123456+00:00"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            states[0].entity_id.as_ref().map(String::as_str),
+            Some("sun.sun")
+        );
+        assert_eq!(states[0].name, "below_horizon");
+        assert!(states[0].last_changed.is_some());
+    }
 }
@@ -2,8 +2,10 @@ use futures::future::Future;
 use futures::sink::Sink;
 use futures::stream::Stream;
 use futures::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-use rumqtt::{MqttClient, MqttOptions, Notification, ReconnectOptions};
+use rumqtt::{LastWill, MqttClient, MqttOptions, Notification, ReconnectOptions};
 
 #[derive(Debug)]
 pub enum Error {
@@ -22,16 +24,82 @@ type Value = String;
 
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl From<QoS> for mqtt311::QoS {
+    fn from(qos: QoS) -> Self {
+        match qos {
+            QoS::AtMostOnce => mqtt311::QoS::AtMostOnce,
+            QoS::AtLeastOnce => mqtt311::QoS::AtLeastOnce,
+            QoS::ExactlyOnce => mqtt311::QoS::ExactlyOnce,
+        }
+    }
+}
+
+/// How a message is published, at least once and not retained by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delivery {
+    pub qos: QoS,
+    // The broker keeps the message and hands it to everyone who subscribes later.
+    pub retain: bool,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery {
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum OpCode {
     MessageReceived((Topic, Value)),
-    Subscribe(Topic),
-    Publish((Topic, Value)),
+    // A retained message, sent by the broker on subscribing. It may be long outdated.
+    RetainedReceived((Topic, Value)),
+    // The connection to the broker was lost and is back, messages may have been missed.
+    Reconnected,
+    Subscribe((Topic, QoS)),
+    Publish((Topic, Value, Delivery)),
+}
+
+/// The last value seen on every subscribed topic.
+#[derive(Clone, Debug, Default)]
+pub struct TopicStates {
+    values: Arc<Mutex<HashMap<Topic, Value>>>,
+}
+
+impl TopicStates {
+    pub fn new() -> Self {
+        TopicStates::default()
+    }
+
+    pub fn update(&self, topic: &str, value: &str) {
+        self.values
+            .lock()
+            .expect("Mutex poisoned")
+            .insert(topic.to_string(), value.to_string());
+    }
+
+    pub fn get(&self, topic: &str) -> Option<Value> {
+        self.values
+            .lock()
+            .expect("Mutex poisoned")
+            .get(topic)
+            .cloned()
+    }
 }
 
 pub struct MqttConnection {
     event_emitter: Sender<OpCode>,
     event_receiver: Receiver<OpCode>,
+    availability: Option<Topic>,
 }
 
 impl MqttConnection {
@@ -42,35 +110,60 @@ impl MqttConnection {
         let m = MqttConnection {
             event_emitter: inner_sender,
             event_receiver: inner_receiver,
+            availability: None,
         };
 
         ((outer_sender, outer_receiver), m)
     }
 
-    pub fn run(self, broker: &str, port: u16) -> Result<()> {
+    /// Announces the daemon on `<prefix>/shutdown/availability`: `online` once it is connected
+    /// and `offline` as the last will, which the broker sends when the connection is lost.
+    pub fn set_prefix(mut self, prefix: &str) -> Self {
+        self.availability = Some(format!("{}/shutdown/availability", prefix));
+        self
+    }
+
+    fn options(&self, broker: &str, port: u16) -> MqttOptions {
         let reconnect_options = ReconnectOptions::Always(5);
         let mqtt_options = MqttOptions::new("space-shutdown-dev", broker, port)
             .set_keep_alive(10)
             .set_reconnect_opts(reconnect_options)
             .set_clean_session(false);
+        match &self.availability {
+            Some(topic) => mqtt_options.set_last_will(LastWill {
+                topic: topic.clone(),
+                message: "offline".to_string(),
+                qos: AVAILABILITY.qos.into(),
+                retain: AVAILABILITY.retain,
+            }),
+            None => mqtt_options,
+        }
+    }
 
-        let (mqtt_client, notifications) = MqttClient::start(mqtt_options)?;
+    pub fn run(self, broker: &str, port: u16) -> Result<()> {
+        let (mut mqtt_client, notifications) = MqttClient::start(self.options(broker, port))?;
+        announce(&mut mqtt_client, &self.availability);
 
         let rx = self.event_receiver;
         let mut loop_client = mqtt_client.clone();
         let ht = std::thread::spawn(move || {
             tokio::run(rx.for_each(move |msg| {
                 match msg {
-                    OpCode::Subscribe(topic) => {
-                        println!("subscribing: {}", topic);
+                    OpCode::Subscribe((topic, qos)) => {
+                        println!("subscribing: {} ({:?})", topic, qos);
                         loop_client
-                            .subscribe(&topic, mqtt311::QoS::AtLeastOnce)
+                            .subscribe(&topic, qos.into())
                             .expect("Failed to subscribe");
                         println!("subscribed");
                     }
-                    OpCode::Publish((topic, value)) => {
+                    OpCode::Publish((topic, value, delivery)) => {
                         loop_client
-                            .publish(topic, mqtt311::QoS::AtLeastOnce, false, value.as_bytes())
+                            .publish(
+                                topic,
+                                delivery.qos.into(),
+                                delivery.retain,
+                                value.as_bytes(),
+                            )
                             .expect("failed to publish");
                     }
                     e => println!("Unimplemented event received: {:?}", e),
@@ -91,16 +184,18 @@ impl MqttConnection {
                         }
                     };
 
-                    let fut = self
-                        .event_emitter
-                        .clone()
-                        .send(OpCode::MessageReceived((
-                            msg.topic_name,
-                            payload.to_string(),
-                        )))
-                        .map(|_| ())
-                        .map_err(|_| ());
-                    tokio::run(futures::lazy(move || fut));
+                    let message = (msg.topic_name, payload.to_string());
+                    if msg.retain {
+                        emit(&self.event_emitter, OpCode::RetainedReceived(message));
+                    } else {
+                        emit(&self.event_emitter, OpCode::MessageReceived(message));
+                    }
+                }
+                Notification::Reconnection => {
+                    println!("reconnected");
+                    // the broker may have sent the last will in between
+                    announce(&mut mqtt_client, &self.availability);
+                    emit(&self.event_emitter, OpCode::Reconnected);
                 }
                 o => {
                     println!("Unhandled notification: {:?}", o);
@@ -112,3 +207,62 @@ impl MqttConnection {
         Ok(())
     }
 }
+
+// Retained, so whoever subscribes later also knows whether the daemon is up.
+const AVAILABILITY: Delivery = Delivery {
+    qos: QoS::AtLeastOnce,
+    retain: true,
+};
+
+fn announcement(availability: &Option<Topic>) -> Option<(Topic, Value, Delivery)> {
+    availability
+        .as_ref()
+        .map(|topic| (topic.clone(), "online".to_string(), AVAILABILITY))
+}
+
+fn announce(client: &mut MqttClient, availability: &Option<Topic>) {
+    if let Some((topic, value, delivery)) = announcement(availability) {
+        client
+            .publish(topic, delivery.qos.into(), delivery.retain, value)
+            .expect("failed to publish");
+    }
+}
+
+fn emit(emitter: &Sender<OpCode>, op: OpCode) {
+    let fut = emitter.clone().send(op).map(|_| ()).map_err(|_| ());
+    tokio::run(futures::lazy(move || fut));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn availability_is_announced_with_a_last_will() {
+        let (_, m) = MqttConnection::new();
+        assert_eq!(m.options("localhost", 1883).last_will(), None);
+        assert_eq!(announcement(&m.availability), None);
+
+        let m = m.set_prefix("w17");
+        assert_eq!(
+            m.options("localhost", 1883).last_will(),
+            Some(LastWill {
+                topic: "w17/shutdown/availability".to_string(),
+                message: "offline".to_string(),
+                qos: mqtt311::QoS::AtLeastOnce,
+                retain: true,
+            })
+        );
+        assert_eq!(
+            announcement(&m.availability),
+            Some((
+                "w17/shutdown/availability".to_string(),
+                "online".to_string(),
+                Delivery {
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                }
+            ))
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::hass::{Attributes, Hass, Instances, ServiceCall, ServiceUse};
+use crate::report::{Outcome, Report};
+use futures::Future;
+use std::time::Duration;
+
+const TITLE: &str = "Space shutdown";
+
+/// The points in the shutdown cycle a notification can be sent for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    CountdownStarted,
+    ShutdownImminent,
+    ShutdownCompleted,
+    ShutdownFailed,
+    SensorFault,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    CountdownStarted { delay: Duration },
+    ShutdownImminent { remaining: Duration },
+    ShutdownCompleted,
+    ShutdownFailed { report: String },
+    // A lock sensor changed its state suspiciously often.
+    SensorFault { sensor: String, changes: usize },
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::CountdownStarted { .. } => EventKind::CountdownStarted,
+            Event::ShutdownImminent { .. } => EventKind::ShutdownImminent,
+            Event::ShutdownCompleted => EventKind::ShutdownCompleted,
+            Event::ShutdownFailed { .. } => EventKind::ShutdownFailed,
+            Event::SensorFault { .. } => EventKind::SensorFault,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Event::CountdownStarted { delay } => format!(
+                "The door has been locked, the space will power down in {}.",
+                human_duration(*delay)
+            ),
+            Event::ShutdownImminent { remaining } => format!(
+                "The space will power down in {}.",
+                human_duration(*remaining)
+            ),
+            Event::ShutdownCompleted => "The space has been powered down.".to_string(),
+            Event::ShutdownFailed { report } => {
+                format!("Powering down the space failed: {}", report)
+            }
+            Event::SensorFault { sensor, changes } => format!(
+                "The lock sensor {} changed {} times in a short while, it may be faulty.",
+                sensor, changes
+            ),
+        }
+    }
+}
+
+fn human_duration(d: Duration) -> String {
+    match d.as_secs() {
+        0..=1 => "one second".to_string(),
+        s if s < 60 => format!("{} seconds", s),
+        s if s < 120 => "one minute".to_string(),
+        s => format!("{} minutes", s / 60),
+    }
+}
+
+/// Where a notification is delivered to.
+#[derive(Debug, Clone)]
+pub enum Notifier {
+    // `notify.<target>`, e.g. a phone or a chat bot
+    Notify(String),
+    // `persistent_notification.create` in the Home Assistant frontend
+    Persistent,
+    // `tts.<engine>_say` on a media player, for the people still in the space
+    Tts {
+        engine: String,
+        media_player: String,
+    },
+}
+
+impl Notifier {
+    /// The service the messages go to, with the fields of `service_call`.
+    pub fn service(&self) -> ServiceUse {
+        match self {
+            Notifier::Notify(target) => ServiceUse::new("notify", target, &["title", "message"]),
+            Notifier::Persistent => ServiceUse::new(
+                "persistent_notification",
+                "create",
+                &["notification_id", "title", "message"],
+            ),
+            Notifier::Tts { engine, .. } => {
+                ServiceUse::new("tts", format!("{}_say", engine), &["entity_id", "message"])
+            }
+        }
+    }
+
+    pub fn service_call(&self, message: &str) -> ServiceCall {
+        match self {
+            Notifier::Notify(target) => ServiceCall::new(
+                "notify",
+                target,
+                Some(
+                    Attributes::new()
+                        .set("title", TITLE)
+                        .set("message", message),
+                ),
+            ),
+            Notifier::Persistent => ServiceCall::new(
+                "persistent_notification",
+                "create",
+                Some(
+                    Attributes::new()
+                        .set("notification_id", "space_shutdown")
+                        .set("title", TITLE)
+                        .set("message", message),
+                ),
+            ),
+            Notifier::Tts {
+                engine,
+                media_player,
+            } => ServiceCall::new(
+                "tts",
+                format!("{}_say", engine),
+                Some(
+                    Attributes::new()
+                        .set("entity_id", media_player.as_str())
+                        .set("message", message),
+                ),
+            ),
+        }
+    }
+}
+
+/// A notifier together with the events it should be told about.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    notifier: Notifier,
+    events: Vec<EventKind>,
+    instance: Option<String>,
+}
+
+impl Notification {
+    pub fn new(notifier: Notifier, events: &[EventKind]) -> Self {
+        Notification {
+            notifier,
+            events: events.to_vec(),
+            instance: None,
+        }
+    }
+
+    /// Sends the notification through the named Home Assistant instance instead of the
+    /// default one.
+    pub fn on(mut self, instance: impl AsRef<str>) -> Self {
+        self.instance = Some(instance.as_ref().to_string());
+        self
+    }
+
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_ref().map(String::as_str)
+    }
+
+    pub fn service(&self) -> ServiceUse {
+        self.notifier.service()
+    }
+
+    pub fn service_call(&self, message: &str) -> ServiceCall {
+        self.notifier.service_call(message)
+    }
+}
+
+/// Sends `event` to every notification that subscribed to it. A failing notification never
+/// fails the future, the outcomes are returned instead.
+pub fn send<H: Hass>(
+    hass: &Instances<H>,
+    notifications: &[Notification],
+    event: &Event,
+) -> Box<dyn Future<Item = Report, Error = ()> + Send> {
+    let message = event.message();
+    let futures = notifications
+        .iter()
+        .filter(|n| n.events.contains(&event.kind()))
+        .map(|n| {
+            let call = n.service_call(&message);
+            let action = format!("notify via {}", call);
+            hass.call(n.instance(), &call).then(move |r| {
+                let result = r.map(|_| ()).map_err(|e| {
+                    println!("failed to send notification: {}", e);
+                    e.to_string()
+                });
+                Ok(Outcome::new(action, result))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Box::new(futures::future::join_all(futures).map(Report::new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hass::FakeHass;
+
+    #[test]
+    fn imminent_message() {
+        let event = Event::ShutdownImminent {
+            remaining: Duration::from_secs(120),
+        };
+        assert_eq!(event.message(), "The space will power down in 2 minutes.");
+    }
+
+    #[test]
+    fn tts_service_call() {
+        let notifier = Notifier::Tts {
+            engine: "google_translate".to_string(),
+            media_player: "media_player.lounge".to_string(),
+        };
+        let call = notifier.service_call("hello");
+        assert_eq!(call.to_string(), "tts.google_translate_say");
+        let data = call.data.unwrap();
+        assert_eq!(data.get("entity_id").unwrap(), "media_player.lounge");
+        assert_eq!(data.get("message").unwrap(), "hello");
+    }
+
+    #[test]
+    fn only_subscribed_notifications_are_sent() {
+        let hass = FakeHass::new();
+        let instances = Instances::new("default", hass.clone());
+        let notifications = vec![
+            Notification::new(Notifier::Persistent, &[EventKind::ShutdownFailed]),
+            Notification::new(
+                Notifier::Notify("space_bot".to_string()),
+                &[EventKind::ShutdownCompleted, EventKind::ShutdownFailed],
+            ),
+        ];
+
+        let report = send(&instances, &notifications, &Event::ShutdownCompleted)
+            .wait()
+            .unwrap();
+
+        assert!(report.is_success());
+        let calls = hass.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].to_string(), "notify.space_bot");
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fmt;
+use std::time::Duration;
+
+/// What a payload template can refer to, at the time the message is sent.
+#[derive(Debug, Clone)]
+pub struct Context<'a> {
+    // e.g. `door locked`
+    pub reason: &'a str,
+    pub timestamp: DateTime<Utc>,
+    pub zone: &'a str,
+    // Until the plan is done, i.e. its last stage is due. Not the countdown, which is over
+    // once the shutdown runs.
+    pub plan_remaining: Duration,
+}
+
+impl<'a> Context<'a> {
+    fn lookup(&self, name: &str) -> Option<serde_json::Value> {
+        match name {
+            "reason" => Some(self.reason.into()),
+            "timestamp" => Some(
+                self.timestamp
+                    .to_rfc3339_opts(SecondsFormat::Secs, true)
+                    .into(),
+            ),
+            "zone" => Some(self.zone.into()),
+            "plan_remaining" => Some(self.plan_remaining.as_secs().into()),
+            _ => None,
+        }
+    }
+
+    // Replaces `{reason}`, `{timestamp}`, `{zone}` and `{plan_remaining}`. Other braces are left
+    // alone, so JSON can be written as text as well.
+    fn render(&self, template: &str) -> String {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest
+                .find('}')
+                .and_then(|end| self.lookup(&rest[1..end]).map(|value| (end, value)));
+            match value {
+                Some((end, value)) => {
+                    match value {
+                        serde_json::Value::String(s) => rendered.push_str(&s),
+                        value => rendered.push_str(&value.to_string()),
+                    }
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    // A string that is a placeholder and nothing else takes the type of the value, so
+    // `"{plan_remaining}"` becomes a number.
+    fn render_json(&self, value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::String(s) => {
+                let placeholder = if s.starts_with('{') && s.ends_with('}') {
+                    self.lookup(&s[1..s.len() - 1])
+                } else {
+                    None
+                };
+                placeholder.unwrap_or_else(|| self.render(s).into())
+            }
+            serde_json::Value::Array(values) => {
+                values.iter().map(|v| self.render_json(v)).collect()
+            }
+            serde_json::Value::Object(values) => values
+                .iter()
+                .map(|(k, v)| (k.clone(), self.render_json(v)))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            value => value.clone(),
+        }
+    }
+}
+
+/// The payload of a message, rendered with the context when it is sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Text(String),
+    Json(serde_json::Value),
+}
+
+impl Payload {
+    pub fn render(&self, context: &Context) -> String {
+        match self {
+            Payload::Text(template) => context.render(template),
+            Payload::Json(value) => context.render_json(value).to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Payload::Text(template) => write!(f, "{}", template),
+            Payload::Json(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn context() -> Context<'static> {
+        Context {
+            reason: "door locked",
+            timestamp: Utc.ymd(2019, 11, 2).and_hms(23, 15, 0),
+            zone: "lounge",
+            plan_remaining: Duration::from_secs(900),
+        }
+    }
+
+    #[test]
+    fn text_templates() {
+        let payload = Payload::Text("{zone} shut down at {timestamp}: {reason}".to_string());
+        assert_eq!(
+            payload.render(&context()),
+            "lounge shut down at 2019-11-02T23:15:00Z: door locked"
+        );
+
+        // not a placeholder
+        let payload = Payload::Text(r#"{"state":"OFF","left":{plan_remaining}}"#.to_string());
+        assert_eq!(payload.render(&context()), r#"{"state":"OFF","left":900}"#);
+    }
+
+    #[test]
+    fn json_templates() {
+        let payload = Payload::Json(serde_json::json!({
+            "effect": "fade_out",
+            "left": "{plan_remaining}",
+            "text": ["shutdown of the {zone}", "{reason}"],
+        }));
+        assert_eq!(
+            payload.render(&context()),
+            r#"{"effect":"fade_out","left":900,"text":["shutdown of the lounge","door locked"]}"#
+        );
+    }
+}
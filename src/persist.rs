@@ -0,0 +1,107 @@
+use crate::snapshot::Snapshot;
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+
+/// The part of the state that has to survive a restart of the daemon. Deadlines are stored as
+/// wall clock time, an `Instant` means nothing to the next process.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Saved {
+    // The door is locked and the shutdown is due at `deadline`. The snapshot is there if the
+    // shutdown had already started, running it again must not capture what it switched off.
+    Armed {
+        deadline: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        snapshot: Option<Snapshot>,
+    },
+    // The shutdown already ran and the door has not been unlocked since.
+    Off {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        snapshot: Option<Snapshot>,
+    },
+}
+
+/// A small JSON file holding the saved state. It is removed while there is nothing to resume.
+#[derive(Debug, Clone)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        StateFile {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The saved state, if there is one. A file that can not be read is treated as missing.
+    pub fn load(&self) -> Option<Saved> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                println!("failed to read {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+        match serde_json::from_str(&content) {
+            Ok(saved) => Some(saved),
+            Err(e) => {
+                println!("ignoring {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Replaces the saved state, `None` removes the file.
+    pub fn save(&self, saved: Option<&Saved>) {
+        let result = match saved {
+            Some(saved) => {
+                // written next to the file and renamed, so a crash never leaves half of it
+                let tmp = self.path.with_extension("tmp");
+                serde_json::to_string(saved)
+                    .map_err(|e| e.to_string())
+                    .and_then(|json| std::fs::write(&tmp, json).map_err(|e| e.to_string()))
+                    .and_then(|_| std::fs::rename(&tmp, &self.path).map_err(|e| e.to_string()))
+            }
+            None => match std::fs::remove_file(&self.path) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                r => r.map_err(|e| e.to_string()),
+            },
+        };
+        if let Err(e) = result {
+            println!("failed to save the state to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_state_is_loaded_until_cleared() {
+        let path = std::env::temp_dir().join(format!("shutdown-state-{}.json", std::process::id()));
+        let file = StateFile::new(&path);
+        assert_eq!(file.load(), None);
+
+        let saved = Saved::Armed {
+            deadline: Utc::now(),
+            snapshot: None,
+        };
+        file.save(Some(&saved));
+        assert_eq!(file.load(), Some(saved));
+
+        // written before snapshots were saved
+        std::fs::write(&path, r#"{"state":"off"}"#).unwrap();
+        assert_eq!(file.load(), Some(Saved::Off { snapshot: None }));
+
+        file.save(None);
+        assert_eq!(file.load(), None);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,415 @@
+use crate::auto_shutdown::{ShutdownMessage, Thermostat};
+use crate::clock::Clock;
+use crate::condition::Condition;
+use crate::confirm::Confirmation;
+use crate::report::{Outcome, Report, ShutdownReport, StageReport, StageResult};
+use futures::future::{self, Either, Future, Shared};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A single thing done during the shutdown.
+#[derive(Clone)]
+pub enum Action {
+    Publish(ShutdownMessage),
+    SetTemperature(Thermostat),
+}
+
+impl Action {
+    pub fn confirmation(&self) -> Option<&Confirmation> {
+        match self {
+            Action::Publish(message) => message.confirmation(),
+            Action::SetTemperature(thermostat) => thermostat.confirmation(),
+        }
+    }
+
+    /// The Home Assistant instance the action goes to, `None` for the default one and for
+    /// MQTT messages.
+    pub fn instance(&self) -> Option<&str> {
+        match self {
+            Action::Publish(_) => None,
+            Action::SetTemperature(thermostat) => thermostat.instance(),
+        }
+    }
+
+    /// Conditions that all have to hold when the action is due, otherwise it is skipped.
+    pub fn conditions(&self) -> &[Condition] {
+        match self {
+            Action::Publish(message) => message.conditions(),
+            Action::SetTemperature(thermostat) => thermostat.conditions(),
+        }
+    }
+}
+
+/// A group of actions that run together, `delay` after the shutdown started and not before the
+/// stages it comes `after` have finished.
+#[derive(Clone)]
+pub struct Stage {
+    name: String,
+    delay: Duration,
+    after: Vec<String>,
+    actions: Vec<Action>,
+}
+
+impl Stage {
+    pub fn new(name: &str, delay: Duration) -> Self {
+        Stage {
+            name: name.to_string(),
+            delay,
+            after: vec![],
+            actions: vec![],
+        }
+    }
+
+    /// Waits for an earlier stage. If that stage fails this one is skipped, so e.g. the
+    /// projector does not lose power before it went to standby.
+    pub fn after(mut self, stage: &str) -> Self {
+        self.after.push(stage.to_string());
+        self
+    }
+
+    pub fn add_action(mut self, action: Action) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    pub fn add_message(self, message: ShutdownMessage) -> Self {
+        self.add_action(Action::Publish(message))
+    }
+
+    pub fn add_thermostat(self, thermostat: Thermostat) -> Self {
+        self.add_action(Action::SetTemperature(thermostat))
+    }
+}
+
+type StageFuture = Shared<Box<dyn Future<Item = StageReport, Error = ()> + Send>>;
+type Cancelled = Shared<Box<dyn Future<Item = String, Error = ()> + Send>>;
+
+/// The ordered stages of a shutdown.
+#[derive(Clone, Default)]
+pub struct Plan {
+    stages: Vec<Stage>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Plan::default()
+    }
+
+    pub fn add_stage(mut self, stage: Stage) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.stages.iter().flat_map(|s| s.actions.iter())
+    }
+
+    /// When the last stage is due, unless it waits for a stage that takes longer.
+    pub fn duration(&self) -> Duration {
+        self.stages
+            .iter()
+            .map(|s| s.delay)
+            .max()
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// Stage names have to be unique and a stage can only wait for stages declared before it,
+    /// which also rules out cycles.
+    pub fn check(&self) -> Result<(), String> {
+        let mut seen: Vec<&str> = vec![];
+        for stage in self.stages.iter() {
+            if seen.contains(&stage.name.as_str()) {
+                return Err(format!("stage {} is declared twice", stage.name));
+            }
+            for after in stage.after.iter() {
+                if !seen.contains(&after.as_str()) {
+                    return Err(format!(
+                        "stage {} waits for {}, which is not declared before it",
+                        stage.name, after
+                    ));
+                }
+            }
+            seen.push(&stage.name);
+        }
+        Ok(())
+    }
+
+    /// Runs all stages, taking `start` as T+0, and reports on every stage. Once `cancel`
+    /// resolves, the stages that have not started yet are skipped with the reason it resolves
+    /// to. Stages that are running finish.
+    pub fn run<C, F>(
+        &self,
+        clock: &Arc<dyn Clock>,
+        start: Instant,
+        cancel: C,
+        run_action: F,
+    ) -> Box<dyn Future<Item = ShutdownReport, Error = ()> + Send>
+    where
+        C: Future<Item = String, Error = ()> + Send + 'static,
+        F: Fn(&Action) -> Box<dyn Future<Item = Outcome, Error = ()> + Send>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let run_action = Arc::new(run_action);
+        let cancel: Box<dyn Future<Item = String, Error = ()> + Send> = Box::new(cancel);
+        let cancelled: Cancelled = cancel.shared();
+        let mut started: HashMap<&str, StageFuture> = HashMap::new();
+        let mut stages = vec![];
+
+        for stage in self.stages.iter() {
+            let dependencies = stage
+                .after
+                .iter()
+                .filter_map(|name| started.get(name.as_str()).cloned())
+                .collect::<Vec<_>>();
+            let name = stage.name.clone();
+            let at = start + stage.delay;
+            let clock = Arc::clone(clock);
+            let actions = stage.actions.clone();
+            let run_action = Arc::clone(&run_action);
+            let cancelled = cancelled.clone();
+
+            let fut = future::join_all(dependencies).then(move |r| {
+                let failed = r
+                    .map(|deps| {
+                        // a cancelled dependency passes on its reason
+                        deps.iter()
+                            .find(|d| !d.is_success())
+                            .map(|d| match &d.result {
+                                StageResult::Skipped(reason) if d.cancelled => {
+                                    (reason.clone(), true)
+                                }
+                                _ => (format!("{} failed", d.stage), false),
+                            })
+                    })
+                    .unwrap_or_else(|_| Some(("an earlier stage failed".to_string(), false)));
+                if let Some((reason, cancelled)) = failed {
+                    println!("skipping stage {}, {}", name, reason);
+                    return Either::A(future::ok(StageReport::skipped(name, reason, cancelled)));
+                }
+
+                let due = clock.delay_until(at).select2(cancelled).then(|r| match r {
+                    Ok(Either::A(_)) => Ok(None),
+                    Ok(Either::B((reason, _))) => Ok(Some((*reason).clone())),
+                    Err(_) => Err(()),
+                });
+                Either::B(due.and_then(move |cancelled| {
+                    if let Some(reason) = cancelled {
+                        println!("skipping stage {}, {}", name, reason);
+                        return Either::A(future::ok(StageReport::skipped(name, reason, true)));
+                    }
+                    println!("running stage {}", name);
+                    let outcomes = actions
+                        .iter()
+                        .map(|action| {
+                            let run_action = Arc::clone(&run_action);
+                            let action = action.clone();
+                            future::lazy(move || run_action(&action))
+                        })
+                        .collect::<Vec<_>>();
+                    Either::B(future::join_all(outcomes).map(move |outcomes| StageReport {
+                        stage: name,
+                        result: StageResult::Ran(Report::new(outcomes)),
+                        cancelled: false,
+                    }))
+                }))
+            });
+            let fut: Box<dyn Future<Item = StageReport, Error = ()> + Send> = Box::new(fut);
+            let fut = fut.shared();
+
+            started.insert(&stage.name, fut.clone());
+            stages.push(fut);
+        }
+
+        Box::new(
+            future::join_all(stages)
+                .map(|stages| ShutdownReport::new(stages.iter().map(|s| (**s).clone()).collect()))
+                .map_err(|_| ()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use futures::sync::mpsc;
+    use futures::Stream;
+
+    fn publish(topic: &str) -> Action {
+        Action::Publish(ShutdownMessage::new(topic, "0"))
+    }
+
+    fn topic(action: &Action) -> String {
+        match action {
+            Action::Publish(message) => message.topic().to_string(),
+            Action::SetTemperature(_) => "heating".to_string(),
+        }
+    }
+
+    #[test]
+    fn check_rejects_unknown_and_later_stages() {
+        let plan = Plan::new()
+            .add_stage(Stage::new("power", Duration::from_secs(0)).after("standby"))
+            .add_stage(Stage::new("standby", Duration::from_secs(0)));
+        assert!(plan.check().is_err());
+
+        let plan = Plan::new()
+            .add_stage(Stage::new("lights", Duration::from_secs(0)))
+            .add_stage(Stage::new("lights", Duration::from_secs(0)));
+        assert!(plan.check().is_err());
+    }
+
+    #[test]
+    fn stages_run_at_their_delay() {
+        let clock = ManualClock::new(chrono::Utc::now());
+        let dyn_clock: Arc<dyn Clock> = Arc::new(clock.clone());
+        let plan = Plan::new()
+            .add_stage(
+                Stage::new("lights", Duration::from_secs(0))
+                    .add_action(publish("lounge/leds/set"))
+                    .add_action(publish("lounge/amp/set")),
+            )
+            .add_stage(
+                Stage::new("projector_standby", Duration::from_secs(0))
+                    .add_action(publish("lounge/projector/set")),
+            )
+            .add_stage(
+                Stage::new("projector_power", Duration::from_secs(5 * 60))
+                    .after("projector_standby")
+                    .add_action(publish("lounge/projector/power/set")),
+            )
+            .add_stage(
+                Stage::new("heating", Duration::from_secs(15 * 60))
+                    .add_thermostat(Thermostat::new("climate.lounge", 18.0)),
+            );
+        assert!(plan.check().is_ok());
+
+        let (tx, rx) = mpsc::unbounded();
+        let fut = plan.run(
+            &dyn_clock,
+            dyn_clock.now(),
+            future::empty(),
+            move |action| {
+                tx.unbounded_send(topic(action)).unwrap();
+                Box::new(future::ok(Outcome::new(topic(action), Ok(()))))
+            },
+        );
+        let mut fut = futures::executor::spawn(fut);
+        let mut rx = rx.wait();
+
+        // nothing is polled before the stages are run
+        assert!(fut.poll_future_notify(&noop(), 0).unwrap().is_not_ready());
+        assert_eq!(rx.next().unwrap().unwrap(), "lounge/leds/set");
+        assert_eq!(rx.next().unwrap().unwrap(), "lounge/amp/set");
+        assert_eq!(rx.next().unwrap().unwrap(), "lounge/projector/set");
+
+        clock.advance(Duration::from_secs(5 * 60));
+        assert!(fut.poll_future_notify(&noop(), 0).unwrap().is_not_ready());
+        assert_eq!(rx.next().unwrap().unwrap(), "lounge/projector/power/set");
+
+        clock.advance(Duration::from_secs(10 * 60));
+        let report = match fut.poll_future_notify(&noop(), 0).unwrap() {
+            futures::Async::Ready(report) => report,
+            futures::Async::NotReady => panic!("the heating stage did not run"),
+        };
+        assert_eq!(rx.next().unwrap().unwrap(), "heating");
+        assert!(report.is_success());
+        assert_eq!(report.stages.len(), 4);
+        assert_eq!(report.outcomes().count(), 5);
+    }
+
+    #[test]
+    fn cancelling_skips_the_stages_that_have_not_started() {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new(chrono::Utc::now()));
+        let plan = Plan::new()
+            .add_stage(
+                Stage::new("lights", Duration::from_secs(0)).add_action(publish("lounge/leds/set")),
+            )
+            .add_stage(
+                Stage::new("heating", Duration::from_secs(15 * 60))
+                    .add_thermostat(Thermostat::new("climate.lounge", 18.0)),
+            )
+            .add_stage(
+                Stage::new("heating_check", Duration::from_secs(0))
+                    .after("heating")
+                    .add_action(publish("lounge/heating/check")),
+            );
+
+        let (cancel, cancelled) = futures::sync::oneshot::channel();
+        let (tx, rx) = mpsc::unbounded();
+        let fut = plan.run(
+            &clock,
+            clock.now(),
+            cancelled.map_err(|_| ()),
+            move |action| {
+                tx.unbounded_send(topic(action)).unwrap();
+                Box::new(future::ok(Outcome::new(topic(action), Ok(()))))
+            },
+        );
+        let mut fut = futures::executor::spawn(fut);
+
+        assert!(fut.poll_future_notify(&noop(), 0).unwrap().is_not_ready());
+        cancel.send("door unlocked".to_string()).unwrap();
+        let report = match fut.poll_future_notify(&noop(), 0).unwrap() {
+            futures::Async::Ready(report) => report,
+            futures::Async::NotReady => panic!("the plan was not cancelled"),
+        };
+
+        assert_eq!(
+            rx.wait().collect::<Result<Vec<_>, _>>().unwrap(),
+            vec!["lounge/leds/set"]
+        );
+        assert!(report.is_success());
+        for stage in report.stages[1..].iter() {
+            match &stage.result {
+                StageResult::Skipped(reason) => assert_eq!(reason, "door unlocked"),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn failed_dependency_skips_the_stage() {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new(chrono::Utc::now()));
+        let plan = Plan::new()
+            .add_stage(
+                Stage::new("projector_standby", Duration::from_secs(0))
+                    .add_action(publish("lounge/projector/set")),
+            )
+            .add_stage(
+                Stage::new("projector_power", Duration::from_secs(0))
+                    .after("projector_standby")
+                    .add_action(publish("lounge/projector/power/set")),
+            );
+
+        let report = plan
+            .run(&clock, clock.now(), future::empty(), |action| {
+                Box::new(future::ok(Outcome::new(
+                    topic(action),
+                    Err("refused".to_string()),
+                )))
+            })
+            .wait()
+            .unwrap();
+
+        assert_eq!(report.outcomes().count(), 1);
+        match &report.stages[1].result {
+            StageResult::Skipped(reason) => assert_eq!(reason, "projector_standby failed"),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    struct Noop;
+
+    impl futures::executor::Notify for Noop {
+        fn notify(&self, _id: usize) {}
+    }
+
+    fn noop() -> futures::executor::NotifyHandle {
+        static NOOP: Noop = Noop;
+        futures::executor::NotifyHandle::from(&NOOP)
+    }
+}
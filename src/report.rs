@@ -0,0 +1,270 @@
+use std::fmt;
+
+/// The final result of one shutdown action after all retries.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub action: String,
+    pub result: Result<(), String>,
+    // The device that did not confirm the action, even after it was re-issued.
+    pub refused_by: Option<String>,
+    // Why the action was left out, e.g. because one of its conditions did not hold.
+    pub skipped: Option<String>,
+}
+
+impl Outcome {
+    pub fn new(action: impl Into<String>, result: Result<(), String>) -> Self {
+        Outcome {
+            action: action.into(),
+            result,
+            refused_by: None,
+            skipped: None,
+        }
+    }
+
+    pub fn refused(action: impl Into<String>, device: impl Into<String>, error: String) -> Self {
+        Outcome {
+            action: action.into(),
+            result: Err(error),
+            refused_by: Some(device.into()),
+            skipped: None,
+        }
+    }
+
+    pub fn skipped(action: impl Into<String>, reason: String) -> Self {
+        Outcome {
+            action: action.into(),
+            result: Ok(()),
+            refused_by: None,
+            skipped: Some(reason),
+        }
+    }
+}
+
+/// Collects the outcomes of the actions of a shutdown run or one of its stages.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Report {
+    pub fn new(outcomes: Vec<Outcome>) -> Self {
+        Report { outcomes }
+    }
+
+    pub fn extend(&mut self, other: Report) {
+        self.outcomes.extend(other.outcomes);
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &Outcome> {
+        self.outcomes.iter().filter(|o| o.result.is_err())
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} actions, {} failed",
+            self.outcomes.len(),
+            self.failures().count()
+        )?;
+        for outcome in self.failures() {
+            if let Err(e) = &outcome.result {
+                write!(f, "\n  {}: {}", outcome.action, e)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum StageResult {
+    Ran(Report),
+    // The stage did not run, e.g. because a stage it depends on failed.
+    Skipped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct StageReport {
+    pub stage: String,
+    pub result: StageResult,
+    // Skipped because the plan was cancelled, which is not a failure of the plan.
+    pub cancelled: bool,
+}
+
+impl StageReport {
+    pub fn skipped(stage: String, reason: String, cancelled: bool) -> Self {
+        StageReport {
+            stage,
+            result: StageResult::Skipped(reason),
+            cancelled,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        match &self.result {
+            StageResult::Ran(report) => report.is_success(),
+            StageResult::Skipped(_) => false,
+        }
+    }
+}
+
+/// The result of every stage of a shutdown plan, in the order the stages were declared.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownReport {
+    pub stages: Vec<StageReport>,
+}
+
+impl ShutdownReport {
+    pub fn new(stages: Vec<StageReport>) -> Self {
+        ShutdownReport { stages }
+    }
+
+    pub fn outcomes(&self) -> impl Iterator<Item = &Outcome> {
+        self.stages.iter().flat_map(|s| match &s.result {
+            StageResult::Ran(report) => report.outcomes.iter(),
+            StageResult::Skipped(_) => [].iter(),
+        })
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &Outcome> {
+        self.outcomes().filter(|o| o.result.is_err())
+    }
+
+    /// The devices that did not confirm their action.
+    pub fn refused(&self) -> impl Iterator<Item = &str> {
+        self.outcomes()
+            .filter_map(|o| o.refused_by.as_ref().map(String::as_str))
+    }
+
+    /// The actions that were left out on purpose.
+    pub fn skipped(&self) -> impl Iterator<Item = &Outcome> {
+        self.outcomes().filter(|o| o.skipped.is_some())
+    }
+
+    /// Every stage ran without a failure, or was cancelled.
+    pub fn is_success(&self) -> bool {
+        self.stages.iter().all(|s| s.is_success() || s.cancelled)
+    }
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} actions, {} failed",
+            self.outcomes().count(),
+            self.failures().count()
+        )?;
+        let refused = self.refused().collect::<Vec<_>>();
+        if !refused.is_empty() {
+            write!(f, "\n  refused: {}", refused.join(", "))?;
+        }
+        let skipped = self
+            .skipped()
+            .map(|o| o.action.as_str())
+            .collect::<Vec<_>>();
+        if !skipped.is_empty() {
+            write!(f, "\n  skipped: {}", skipped.join(", "))?;
+        }
+        for stage in self.stages.iter() {
+            match &stage.result {
+                StageResult::Ran(report) => {
+                    write!(
+                        f,
+                        "\n  stage {}: {} actions, {} failed",
+                        stage.stage,
+                        report.outcomes.len(),
+                        report.failures().count()
+                    )?;
+                    for outcome in report.failures() {
+                        if let Err(e) = &outcome.result {
+                            write!(f, "\n    {}: {}", outcome.action, e)?;
+                        }
+                    }
+                }
+                StageResult::Skipped(reason) => {
+                    write!(f, "\n  stage {}: skipped, {}", stage.stage, reason)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_lists_failures() {
+        let mut report = Report::new(vec![Outcome::new("publish a/set 0", Ok(()))]);
+        report.extend(Report::new(vec![Outcome::new(
+            "set climate.lounge to 18",
+            Err("timed out".to_string()),
+        )]));
+
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_string(),
+            "2 actions, 1 failed\n  set climate.lounge to 18: timed out"
+        );
+    }
+
+    #[test]
+    fn shutdown_report_lists_refusing_devices() {
+        let report = ShutdownReport::new(vec![StageReport {
+            stage: "lights".to_string(),
+            result: StageResult::Ran(Report::new(vec![
+                Outcome::new("publish lounge/leds/set 0", Ok(())),
+                Outcome::refused(
+                    "publish lounge/amp/set 0",
+                    "lounge/amp/set",
+                    "expected 0, got 1".to_string(),
+                ),
+            ])),
+            cancelled: false,
+        }]);
+
+        assert_eq!(report.refused().collect::<Vec<_>>(), vec!["lounge/amp/set"]);
+        assert_eq!(
+            report.to_string(),
+            "2 actions, 1 failed\n  \
+             refused: lounge/amp/set\n  \
+             stage lights: 2 actions, 1 failed\n    \
+             publish lounge/amp/set 0: expected 0, got 1"
+        );
+    }
+
+    #[test]
+    fn shutdown_report_lists_stages() {
+        let report = ShutdownReport::new(vec![
+            StageReport {
+                stage: "projector_standby".to_string(),
+                result: StageResult::Ran(Report::new(vec![Outcome::new(
+                    "publish lounge/projector/set standby",
+                    Err("connection lost".to_string()),
+                )])),
+                cancelled: false,
+            },
+            StageReport::skipped(
+                "projector_power".to_string(),
+                "projector_standby failed".to_string(),
+                false,
+            ),
+        ]);
+
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_string(),
+            "1 actions, 1 failed\n  \
+             stage projector_standby: 1 actions, 1 failed\n    \
+             publish lounge/projector/set standby: connection lost\n  \
+             stage projector_power: skipped, projector_standby failed"
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use crate::hass::{self, Attributes, Hass, Instances, ServiceCall, ServiceUse};
+use crate::mqtt::{Delivery, OpCode, TopicStates};
+use crate::report::{Outcome, Report};
+use futures::future::{self, Future};
+use futures::sink::Sink;
+use futures::sync::mpsc;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    // The value last seen on `state`, restored by publishing it to `command`.
+    Mqtt { state: String, command: String },
+    // The `temperature` attribute of a climate entity, restored with `climate.set_temperature`.
+    Setpoint { entity: String },
+    // An entity that is either `on` or `off`, restored with `homeassistant.turn_on`/`turn_off`.
+    Switch { entity: String },
+}
+
+/// Something that is recorded just before the shutdown and, if `restore` is set, put back when
+/// the door is unlocked again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capture {
+    source: Source,
+    instance: Option<String>,
+    restore: bool,
+}
+
+impl Capture {
+    pub fn mqtt(state: &str, command: &str) -> Self {
+        Capture::new(Source::Mqtt {
+            state: state.to_string(),
+            command: command.to_string(),
+        })
+    }
+
+    pub fn setpoint(entity: &str) -> Self {
+        Capture::new(Source::Setpoint {
+            entity: entity.to_string(),
+        })
+    }
+
+    pub fn switch(entity: &str) -> Self {
+        Capture::new(Source::Switch {
+            entity: entity.to_string(),
+        })
+    }
+
+    fn new(source: Source) -> Self {
+        Capture {
+            source,
+            instance: None,
+            restore: true,
+        }
+    }
+
+    /// Reads and restores the entity through the named Home Assistant instance instead of the
+    /// default one.
+    pub fn on(mut self, instance: &str) -> Self {
+        self.instance = Some(instance.to_string());
+        self
+    }
+
+    /// Only records the state, e.g. for the printer that should stay off.
+    pub fn without_restore(mut self) -> Self {
+        self.restore = false;
+        self
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_ref().map(String::as_str)
+    }
+
+    /// The Home Assistant services a restore may call, whatever the captured value.
+    pub fn services(&self) -> Vec<ServiceUse> {
+        match &self.source {
+            Source::Mqtt { .. } => vec![],
+            Source::Setpoint { .. } => vec![ServiceUse::new(
+                "climate",
+                "set_temperature",
+                &["entity_id", "temperature"],
+            )],
+            Source::Switch { .. } => vec![
+                ServiceUse::new("homeassistant", "turn_on", &["entity_id"]),
+                ServiceUse::new("homeassistant", "turn_off", &["entity_id"]),
+            ],
+        }
+    }
+
+    /// The Home Assistant call that restores `value`, if this is restored through Home Assistant.
+    pub fn service_call(&self, value: &str) -> Option<ServiceCall> {
+        match &self.source {
+            Source::Mqtt { .. } => None,
+            Source::Setpoint { entity } => value
+                .parse::<f32>()
+                .ok()
+                .map(|temperature| hass::set_temperature_call(entity, temperature)),
+            Source::Switch { entity } => {
+                let service = match value {
+                    "on" => "turn_on",
+                    "off" => "turn_off",
+                    _ => return None,
+                };
+                Some(ServiceCall::new(
+                    "homeassistant",
+                    service,
+                    Some(Attributes::new().set("entity_id", entity.as_str())),
+                ))
+            }
+        }
+    }
+
+    fn describe(&self, value: &str) -> String {
+        match &self.source {
+            Source::Mqtt { command, .. } => format!("restore {} {}", command, value),
+            Source::Setpoint { entity } | Source::Switch { entity } => {
+                format!("restore {} to {}", entity, value)
+            }
+        }
+    }
+
+    fn read<H: Hass>(
+        &self,
+        hass: &Instances<H>,
+        topics: &TopicStates,
+    ) -> Box<dyn Future<Item = Option<String>, Error = ()> + Send> {
+        let entity = match &self.source {
+            Source::Mqtt { state, .. } => return Box::new(future::ok(topics.get(state))),
+            Source::Setpoint { entity } | Source::Switch { entity } => entity.clone(),
+        };
+        let hass = match hass.get(self.instance()) {
+            Ok(hass) => hass,
+            Err(e) => {
+                println!("failed to capture {}: {}", entity, e);
+                return Box::new(future::ok(None));
+            }
+        };
+        let setpoint = match self.source {
+            Source::Setpoint { .. } => true,
+            _ => false,
+        };
+        Box::new(hass.get_state(&entity).then(move |r| {
+            match r {
+                Ok(state) if setpoint => Ok(state
+                    .attributes
+                    .as_ref()
+                    .and_then(|a| a.get("temperature"))
+                    .map(|t| {
+                        t.as_str()
+                            .map(String::from)
+                            .unwrap_or_else(|| t.to_string())
+                    })),
+                Ok(state) => Ok(Some(state.name)),
+                Err(e) => {
+                    println!("failed to capture {}: {}", entity, e);
+                    Ok(None)
+                }
+            }
+        }))
+    }
+}
+
+/// The recorded values. Captures that could not be read are left out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub values: Vec<(Capture, String)>,
+}
+
+impl Snapshot {
+    pub fn take<H: Hass>(
+        captures: &[Capture],
+        hass: &Instances<H>,
+        topics: &TopicStates,
+    ) -> Box<dyn Future<Item = Snapshot, Error = ()> + Send> {
+        let reads = captures
+            .iter()
+            .cloned()
+            .map(|capture| {
+                capture
+                    .read(hass, topics)
+                    .map(move |value| (capture, value))
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(reads).map(|values| {
+            Snapshot {
+                values: values
+                    .into_iter()
+                    .filter_map(|(capture, value)| value.map(|value| (capture, value)))
+                    .collect(),
+            }
+        }))
+    }
+
+    /// Values for the command topics in `retained` are published retained, so they replace the
+    /// retained message of the shutdown.
+    pub fn restore<H: Hass>(
+        &self,
+        hass: &Instances<H>,
+        sender: &mpsc::Sender<OpCode>,
+        retained: &HashSet<String>,
+    ) -> Box<dyn Future<Item = Report, Error = ()> + Send> {
+        let restores = self
+            .values
+            .iter()
+            .filter(|(capture, _)| capture.restore)
+            .map(|(capture, value)| {
+                let action = capture.describe(value);
+                let fut: Box<dyn Future<Item = Result<(), String>, Error = ()> + Send> =
+                    match (&capture.source, capture.service_call(value)) {
+                        (Source::Mqtt { command, .. }, _) => Box::new(
+                            sender
+                                .clone()
+                                .send(OpCode::Publish((
+                                    command.clone(),
+                                    value.clone(),
+                                    Delivery {
+                                        retain: retained.contains(command),
+                                        ..Delivery::default()
+                                    },
+                                )))
+                                .then(|r| Ok(r.map(|_| ()).map_err(|e| e.to_string()))),
+                        ),
+                        (_, Some(call)) => Box::new(
+                            hass.call(capture.instance(), &call)
+                                .then(|r| Ok(r.map(|_| ()).map_err(|e| e.to_string()))),
+                        ),
+                        (_, None) => Box::new(future::ok(Err(format!(
+                            "can not restore the value {}",
+                            value
+                        )))),
+                    };
+                fut.map(move |result| Outcome::new(action, result))
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(restores).map(Report::new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hass::FakeHass;
+    use futures::Stream;
+
+    #[test]
+    fn snapshot_and_restore_a_subset() {
+        let hass = FakeHass::new()
+            .with_state(
+                "climate.lounge",
+                "heat",
+                Some(Attributes::new().set("temperature", 21.5)),
+            )
+            .with_state("light.leds", "on", None);
+        let instances = Instances::new("default", hass.clone());
+        let topics = TopicStates::new();
+        topics.update("lounge/printer/state", "1");
+        let captures = vec![
+            Capture::setpoint("climate.lounge"),
+            Capture::switch("light.leds"),
+            Capture::mqtt("lounge/printer/state", "lounge/printer/set").without_restore(),
+            // never seen, left out
+            Capture::mqtt("lounge/amp/state", "lounge/amp/set"),
+        ];
+
+        let snapshot = Snapshot::take(&captures, &instances, &topics)
+            .wait()
+            .unwrap();
+        assert_eq!(snapshot.values.len(), 3);
+        assert_eq!(snapshot.values[0].1, "21.5");
+
+        let (tx, rx) = mpsc::channel(4);
+        let report = snapshot
+            .restore(&instances, &tx, &HashSet::new())
+            .wait()
+            .unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.outcomes.len(), 2);
+
+        let calls = hass.calls();
+        assert_eq!(calls[0].to_string(), "climate.set_temperature");
+        assert_eq!(
+            calls[0].data.as_ref().unwrap().get("temperature").unwrap(),
+            21.5
+        );
+        assert_eq!(calls[1].to_string(), "homeassistant.turn_on");
+
+        // the printer stays off
+        drop(tx);
+        assert_eq!(rx.collect().wait().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,583 @@
+use crate::notify;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    // The door is open, nothing is going to happen.
+    Idle,
+    // The door is locked and the countdown is running.
+    Armed { deadline: Instant },
+    // The countdown is about to run out and the people in the space have been warned.
+    Warning { deadline: Instant },
+    // The shutdown actions are running. Unlocking the door cancels the stages that have not
+    // started yet, the running ones finish.
+    ShuttingDown { locked: bool, cancelled: bool },
+    // The shutdown ran and the door is still locked.
+    Off,
+    // The countdown ran out but a veto held, the vetoes are checked again at `recheck`.
+    Vetoed { recheck: Instant },
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Idle => "idle",
+            State::Armed { .. } => "armed",
+            State::Warning { .. } => "warning",
+            State::ShuttingDown { .. } => "shutting_down",
+            State::Off => "off",
+            State::Vetoed { .. } => "vetoed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Locked,
+    Unlocked,
+    // A countdown saved by an earlier run of the daemon. The deadline may already have passed.
+    Resumed { deadline: Instant },
+    // The shutdown ran before a restart, the door is still locked.
+    ResumedOff,
+    // A timer requested through `Effect::StartTimer` expired.
+    Timer,
+    VetoesChecked { vetoed_by: Option<String> },
+    // `failure` holds the report if any of the actions failed.
+    ShutdownFinished { failure: Option<String> },
+}
+
+/// What the owner of the state machine has to do after a transition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    // Send `Event::Timer` at the given instant, replacing any timer that is still pending.
+    StartTimer(Instant),
+    CancelTimer,
+    Notify(notify::Event),
+    // Evaluate the vetoes and report back with `Event::VetoesChecked`.
+    CheckVetoes,
+    // Run the shutdown actions and report back with `Event::ShutdownFinished`.
+    RunShutdown,
+    // Skip the stages of the running shutdown that have not started yet.
+    CancelShutdown,
+    // Put back what was recorded before the shutdown, the door has been unlocked again. With
+    // `open` the opening plan runs afterwards.
+    Restore { open: bool },
+}
+
+/// The door lock logic of `AutoShutdown`, free of any I/O. The current time is passed in with
+/// every event so all transitions can be tested without waiting for real timers.
+#[derive(Debug)]
+pub struct StateMachine {
+    state: State,
+    delay: Duration,
+    warning: Duration,
+    veto_recheck: Duration,
+}
+
+impl StateMachine {
+    pub fn new(delay: Duration, warning: Duration, veto_recheck: Duration) -> Self {
+        StateMachine {
+            state: State::Idle,
+            delay,
+            warning,
+            veto_recheck,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    // When the warning is due, if there is to be one at all.
+    fn warning_at(&self, deadline: Instant) -> Option<Instant> {
+        if self.warning > Duration::from_secs(0) && self.warning < self.delay {
+            Some(deadline - self.warning)
+        } else {
+            None
+        }
+    }
+
+    pub fn handle(&mut self, event: Event, now: Instant) -> Vec<Effect> {
+        let (state, effects) = match (self.state, event) {
+            (State::Idle, Event::Locked) => {
+                let deadline = now + self.delay;
+                let timer = self.warning_at(deadline).unwrap_or(deadline);
+                (
+                    State::Armed { deadline },
+                    vec![
+                        Effect::Notify(notify::Event::CountdownStarted { delay: self.delay }),
+                        Effect::StartTimer(timer),
+                    ],
+                )
+            }
+
+            // No new countdown notification, it went out when the door was locked.
+            (State::Idle, Event::Resumed { deadline }) => {
+                let timer = self.warning_at(deadline).unwrap_or(deadline);
+                (State::Armed { deadline }, vec![Effect::StartTimer(timer)])
+            }
+
+            // Locking the door again must not run the shutdown twice.
+            (State::Idle, Event::ResumedOff) => (State::Off, vec![]),
+
+            (State::Armed { deadline }, Event::Timer) if now < deadline => {
+                match self.warning_at(deadline) {
+                    Some(warning_at) if now >= warning_at => (
+                        State::Warning { deadline },
+                        vec![
+                            Effect::Notify(notify::Event::ShutdownImminent {
+                                remaining: deadline - now,
+                            }),
+                            Effect::StartTimer(deadline),
+                        ],
+                    ),
+                    // woken up too early, keep waiting
+                    Some(warning_at) => (self.state, vec![Effect::StartTimer(warning_at)]),
+                    None => (self.state, vec![Effect::StartTimer(deadline)]),
+                }
+            }
+            (State::Warning { deadline }, Event::Timer) if now < deadline => {
+                (self.state, vec![Effect::StartTimer(deadline)])
+            }
+            (State::Armed { .. }, Event::Timer) | (State::Warning { .. }, Event::Timer) => {
+                (self.state, vec![Effect::CheckVetoes])
+            }
+            (State::Vetoed { recheck }, Event::Timer) if now >= recheck => {
+                (self.state, vec![Effect::CheckVetoes])
+            }
+            (State::Vetoed { recheck }, Event::Timer) => {
+                (self.state, vec![Effect::StartTimer(recheck)])
+            }
+
+            // Veto results only count once the countdown ran out. Anything else is the answer
+            // to a check that was overtaken by the door being unlocked and locked again.
+            (State::Armed { deadline }, Event::VetoesChecked { vetoed_by })
+            | (State::Warning { deadline }, Event::VetoesChecked { vetoed_by })
+                if now >= deadline =>
+            {
+                self.vetoes_checked(vetoed_by, now)
+            }
+            (State::Vetoed { .. }, Event::VetoesChecked { vetoed_by }) => {
+                self.vetoes_checked(vetoed_by, now)
+            }
+
+            (State::Armed { .. }, Event::Unlocked)
+            | (State::Warning { .. }, Event::Unlocked)
+            | (State::Vetoed { .. }, Event::Unlocked) => (State::Idle, vec![Effect::CancelTimer]),
+
+            (
+                State::ShuttingDown {
+                    cancelled: false, ..
+                },
+                Event::Unlocked,
+            ) => (
+                State::ShuttingDown {
+                    locked: false,
+                    cancelled: true,
+                },
+                vec![Effect::CancelShutdown],
+            ),
+            // the stages are cancelled already, only the door has to be tracked
+            (
+                State::ShuttingDown {
+                    cancelled: true, ..
+                },
+                Event::Unlocked,
+            ) => (
+                State::ShuttingDown {
+                    locked: false,
+                    cancelled: true,
+                },
+                vec![],
+            ),
+            (State::ShuttingDown { cancelled, .. }, Event::Locked) => (
+                State::ShuttingDown {
+                    locked: true,
+                    cancelled,
+                },
+                vec![],
+            ),
+            (State::ShuttingDown { locked, cancelled }, Event::ShutdownFinished { failure }) => {
+                let notification = match failure {
+                    None => notify::Event::ShutdownCompleted,
+                    Some(report) => notify::Event::ShutdownFailed { report },
+                };
+                if locked && !cancelled {
+                    (State::Off, vec![Effect::Notify(notification)])
+                } else if locked {
+                    // locked again after the shutdown was cut short, it takes a new countdown
+                    let deadline = now + self.delay;
+                    let timer = self.warning_at(deadline).unwrap_or(deadline);
+                    (
+                        State::Armed { deadline },
+                        vec![
+                            Effect::Notify(notification),
+                            Effect::Restore { open: false },
+                            Effect::Notify(notify::Event::CountdownStarted { delay: self.delay }),
+                            Effect::StartTimer(timer),
+                        ],
+                    )
+                } else {
+                    (
+                        State::Idle,
+                        vec![
+                            Effect::Notify(notification),
+                            Effect::Restore { open: false },
+                        ],
+                    )
+                }
+            }
+
+            // Only a completed shutdown is followed by the opening plan.
+            (State::Off, Event::Unlocked) => (State::Idle, vec![Effect::Restore { open: true }]),
+
+            // Locking a locked door, unlocking an open one, stale timers and veto results: there
+            // is nothing to do.
+            (state, event) => {
+                println!("Ignoring {:?} in state {:?}", event, state);
+                (state, vec![])
+            }
+        };
+
+        if state != self.state {
+            println!("{} -> {}", self.state.name(), state.name());
+        }
+        self.state = state;
+        effects
+    }
+
+    fn vetoes_checked(&self, vetoed_by: Option<String>, now: Instant) -> (State, Vec<Effect>) {
+        match vetoed_by {
+            None => (
+                State::ShuttingDown {
+                    locked: true,
+                    cancelled: false,
+                },
+                vec![Effect::RunShutdown],
+            ),
+            Some(veto) => {
+                println!("shutdown vetoed by {}", veto);
+                let recheck = now + self.veto_recheck;
+                (State::Vetoed { recheck }, vec![Effect::StartTimer(recheck)])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_secs(600);
+    const WARNING: Duration = Duration::from_secs(120);
+    const RECHECK: Duration = Duration::from_secs(300);
+
+    fn armed_machine(t0: Instant) -> StateMachine {
+        let mut machine = StateMachine::new(DELAY, WARNING, RECHECK);
+        machine.handle(Event::Locked, t0);
+        machine
+    }
+
+    #[test]
+    fn locking_arms_the_timer() {
+        let t0 = Instant::now();
+        let mut machine = StateMachine::new(DELAY, WARNING, RECHECK);
+
+        let effects = machine.handle(Event::Locked, t0);
+
+        assert_eq!(
+            machine.state(),
+            State::Armed {
+                deadline: t0 + DELAY
+            }
+        );
+        assert_eq!(
+            effects,
+            vec![
+                Effect::Notify(notify::Event::CountdownStarted { delay: DELAY }),
+                Effect::StartTimer(t0 + DELAY - WARNING),
+            ]
+        );
+    }
+
+    #[test]
+    fn countdown_warns_and_shuts_down() {
+        let t0 = Instant::now();
+        let mut machine = armed_machine(t0);
+
+        let effects = machine.handle(Event::Timer, t0 + DELAY - WARNING);
+        assert_eq!(
+            machine.state(),
+            State::Warning {
+                deadline: t0 + DELAY
+            }
+        );
+        assert_eq!(
+            effects,
+            vec![
+                Effect::Notify(notify::Event::ShutdownImminent { remaining: WARNING }),
+                Effect::StartTimer(t0 + DELAY),
+            ]
+        );
+
+        let effects = machine.handle(Event::Timer, t0 + DELAY);
+        assert_eq!(effects, vec![Effect::CheckVetoes]);
+
+        let effects = machine.handle(Event::VetoesChecked { vetoed_by: None }, t0 + DELAY);
+        assert_eq!(
+            machine.state(),
+            State::ShuttingDown {
+                locked: true,
+                cancelled: false
+            }
+        );
+        assert_eq!(effects, vec![Effect::RunShutdown]);
+
+        let effects = machine.handle(Event::ShutdownFinished { failure: None }, t0 + DELAY);
+        assert_eq!(machine.state(), State::Off);
+        assert_eq!(
+            effects,
+            vec![Effect::Notify(notify::Event::ShutdownCompleted)]
+        );
+
+        assert_eq!(
+            machine.handle(Event::Unlocked, t0 + DELAY * 2),
+            vec![Effect::Restore { open: true }]
+        );
+        assert_eq!(machine.state(), State::Idle);
+    }
+
+    #[test]
+    fn no_warning_if_it_does_not_fit_the_delay() {
+        let t0 = Instant::now();
+        let mut machine = StateMachine::new(DELAY, DELAY * 2, RECHECK);
+        let effects = machine.handle(Event::Locked, t0);
+        assert_eq!(effects[1], Effect::StartTimer(t0 + DELAY));
+        assert_eq!(
+            machine.handle(Event::Timer, t0 + DELAY),
+            vec![Effect::CheckVetoes]
+        );
+    }
+
+    #[test]
+    fn early_timer_keeps_waiting() {
+        let t0 = Instant::now();
+        let mut machine = armed_machine(t0);
+        let effects = machine.handle(Event::Timer, t0 + Duration::from_secs(1));
+        assert_eq!(
+            machine.state(),
+            State::Armed {
+                deadline: t0 + DELAY
+            }
+        );
+        assert_eq!(effects, vec![Effect::StartTimer(t0 + DELAY - WARNING)]);
+    }
+
+    #[test]
+    fn unlocking_cancels_the_countdown() {
+        let t0 = Instant::now();
+        let mut machine = armed_machine(t0);
+        let effects = machine.handle(Event::Unlocked, t0 + Duration::from_secs(10));
+        assert_eq!(machine.state(), State::Idle);
+        assert_eq!(effects, vec![Effect::CancelTimer]);
+
+        let mut machine = armed_machine(t0);
+        machine.handle(Event::Timer, t0 + DELAY - WARNING);
+        assert_eq!(
+            machine.handle(Event::Unlocked, t0 + DELAY - WARNING),
+            vec![Effect::CancelTimer]
+        );
+        assert_eq!(machine.state(), State::Idle);
+    }
+
+    #[test]
+    fn veto_rechecks_later() {
+        let t0 = Instant::now();
+        let mut machine = armed_machine(t0);
+        machine.handle(Event::Timer, t0 + DELAY);
+
+        let vetoed = Event::VetoesChecked {
+            vetoed_by: Some("template".to_string()),
+        };
+        let effects = machine.handle(vetoed.clone(), t0 + DELAY);
+        let recheck = t0 + DELAY + RECHECK;
+        assert_eq!(machine.state(), State::Vetoed { recheck });
+        assert_eq!(effects, vec![Effect::StartTimer(recheck)]);
+
+        assert_eq!(
+            machine.handle(Event::Timer, recheck),
+            vec![Effect::CheckVetoes]
+        );
+        machine.handle(Event::VetoesChecked { vetoed_by: None }, recheck);
+        assert_eq!(
+            machine.state(),
+            State::ShuttingDown {
+                locked: true,
+                cancelled: false
+            }
+        );
+
+        let mut machine = armed_machine(t0);
+        machine.handle(Event::Timer, t0 + DELAY);
+        machine.handle(vetoed, t0 + DELAY);
+        assert_eq!(
+            machine.handle(Event::Unlocked, recheck),
+            vec![Effect::CancelTimer]
+        );
+        assert_eq!(machine.state(), State::Idle);
+    }
+
+    #[test]
+    fn stale_veto_result_is_ignored() {
+        let t0 = Instant::now();
+        let mut machine = armed_machine(t0);
+        machine.handle(Event::Timer, t0 + DELAY);
+        // unlocked and locked again while the vetoes were being checked
+        machine.handle(Event::Unlocked, t0 + DELAY);
+        let t1 = t0 + DELAY + Duration::from_secs(1);
+        machine.handle(Event::Locked, t1);
+
+        let effects = machine.handle(Event::VetoesChecked { vetoed_by: None }, t1);
+        assert_eq!(effects, vec![]);
+        assert_eq!(
+            machine.state(),
+            State::Armed {
+                deadline: t1 + DELAY
+            }
+        );
+    }
+
+    #[test]
+    fn unlocking_during_the_shutdown() {
+        let t0 = Instant::now();
+        let mut machine = armed_machine(t0);
+        machine.handle(Event::Timer, t0 + DELAY);
+        machine.handle(Event::VetoesChecked { vetoed_by: None }, t0 + DELAY);
+
+        // the delayed stages are dropped, a second unlock has nothing left to cancel
+        assert_eq!(
+            machine.handle(Event::Unlocked, t0 + DELAY),
+            vec![Effect::CancelShutdown]
+        );
+        assert_eq!(machine.handle(Event::Unlocked, t0 + DELAY), vec![]);
+        let effects = machine.handle(
+            Event::ShutdownFinished {
+                failure: Some("1 actions, 1 failed".to_string()),
+            },
+            t0 + DELAY,
+        );
+        assert_eq!(machine.state(), State::Idle);
+        assert_eq!(
+            effects,
+            vec![
+                Effect::Notify(notify::Event::ShutdownFailed {
+                    report: "1 actions, 1 failed".to_string()
+                }),
+                Effect::Restore { open: false }
+            ]
+        );
+    }
+
+    #[test]
+    fn locking_after_a_cancelled_shutdown_starts_over() {
+        let t0 = Instant::now();
+        let mut machine = armed_machine(t0);
+        machine.handle(Event::Timer, t0 + DELAY);
+        machine.handle(Event::VetoesChecked { vetoed_by: None }, t0 + DELAY);
+        machine.handle(Event::Unlocked, t0 + DELAY);
+        assert_eq!(machine.handle(Event::Locked, t0 + DELAY), vec![]);
+
+        let t1 = t0 + DELAY + Duration::from_secs(1);
+        let effects = machine.handle(Event::ShutdownFinished { failure: None }, t1);
+        assert_eq!(
+            machine.state(),
+            State::Armed {
+                deadline: t1 + DELAY
+            }
+        );
+        assert_eq!(
+            effects,
+            vec![
+                Effect::Notify(notify::Event::ShutdownCompleted),
+                Effect::Restore { open: false },
+                Effect::Notify(notify::Event::CountdownStarted { delay: DELAY }),
+                Effect::StartTimer(t1 + DELAY - WARNING),
+            ]
+        );
+    }
+
+    #[test]
+    fn unlocking_again_after_a_cancelled_shutdown() {
+        let t0 = Instant::now();
+        let mut machine = armed_machine(t0);
+        machine.handle(Event::Timer, t0 + DELAY);
+        machine.handle(Event::VetoesChecked { vetoed_by: None }, t0 + DELAY);
+        machine.handle(Event::Unlocked, t0 + DELAY);
+        machine.handle(Event::Locked, t0 + DELAY);
+        assert_eq!(machine.handle(Event::Unlocked, t0 + DELAY), vec![]);
+
+        // the door is open, no new countdown
+        let effects = machine.handle(Event::ShutdownFinished { failure: None }, t0 + DELAY);
+        assert_eq!(machine.state(), State::Idle);
+        assert_eq!(
+            effects,
+            vec![
+                Effect::Notify(notify::Event::ShutdownCompleted),
+                Effect::Restore { open: false },
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_lock_and_unlock_are_ignored() {
+        let t0 = Instant::now();
+        let mut machine = StateMachine::new(DELAY, WARNING, RECHECK);
+        assert_eq!(machine.handle(Event::Unlocked, t0), vec![]);
+        assert_eq!(machine.state(), State::Idle);
+
+        let mut machine = armed_machine(t0);
+        assert_eq!(machine.handle(Event::Locked, t0 + WARNING), vec![]);
+        assert_eq!(
+            machine.state(),
+            State::Armed {
+                deadline: t0 + DELAY
+            }
+        );
+    }
+
+    #[test]
+    fn resumed_countdown_continues_or_runs_out() {
+        let t0 = Instant::now();
+        let mut machine = StateMachine::new(DELAY, WARNING, RECHECK);
+        let effects = machine.handle(
+            Event::Resumed {
+                deadline: t0 + DELAY,
+            },
+            t0,
+        );
+        assert_eq!(effects, vec![Effect::StartTimer(t0 + DELAY - WARNING)]);
+
+        // restarted after the deadline, the vetoes are checked right away
+        let mut machine = StateMachine::new(DELAY, WARNING, RECHECK);
+        let deadline = t0 + DELAY;
+        let effects = machine.handle(Event::Resumed { deadline }, t0 + DELAY * 2);
+        assert_eq!(effects, vec![Effect::StartTimer(deadline - WARNING)]);
+        assert_eq!(
+            machine.handle(Event::Timer, t0 + DELAY * 2),
+            vec![Effect::CheckVetoes]
+        );
+    }
+
+    #[test]
+    fn shutdown_does_not_run_twice_after_a_restart() {
+        let t0 = Instant::now();
+        let mut machine = StateMachine::new(DELAY, WARNING, RECHECK);
+        assert_eq!(machine.handle(Event::ResumedOff, t0), vec![]);
+        assert_eq!(machine.handle(Event::Locked, t0), vec![]);
+        assert_eq!(machine.state(), State::Off);
+        assert_eq!(
+            machine.handle(Event::Unlocked, t0),
+            vec![Effect::Restore { open: true }]
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use crate::auto_shutdown::AutoShutdown;
+use crate::hass::Hass;
+use crate::mqtt::{OpCode, QoS};
+use crate::persist::StateFile;
+use futures::future::{self, Future};
+use futures::sink::Sink;
+use futures::sync::mpsc;
+
+/// Independent parts of the space, e.g. the workshop and the lounge. Every zone has its own
+/// doors, delay, plan, vetoes and status topic, they share the MQTT connection and the Home
+/// Assistant instances.
+pub struct Zones<H> {
+    zones: Vec<(String, AutoShutdown<H>)>,
+    sender: mpsc::Sender<OpCode>,
+}
+
+impl<H: Hass + Send + Sync + 'static> Zones<H> {
+    pub fn new(sender: mpsc::Sender<OpCode>) -> Self {
+        Zones {
+            zones: vec![],
+            sender,
+        }
+    }
+
+    /// The zone is known by `name`, also to its payload templates.
+    pub fn add(mut self, name: &str, zone: AutoShutdown<H>) -> Self {
+        self.zones.push((name.to_string(), zone.set_zone(name)));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AutoShutdown<H>> {
+        self.zones
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, zone)| zone)
+    }
+
+    /// The topics of all zones, each of them once with the highest QoS any zone asks for.
+    pub fn subscriptions(&self) -> Vec<(String, QoS)> {
+        let mut topics: Vec<(String, QoS)> = vec![];
+        for (_, zone) in self.zones.iter() {
+            let qos = zone.subscription_qos();
+            for topic in zone.subscriptions() {
+                match topics.iter_mut().find(|(t, _)| *t == topic) {
+                    Some((_, q)) if *q < qos => *q = qos,
+                    Some(_) => {}
+                    None => topics.push((topic, qos)),
+                }
+            }
+        }
+        topics
+    }
+
+    pub fn subscribe(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let subscriptions = self.subscriptions().into_iter().map(OpCode::Subscribe);
+        Box::new(
+            self.sender
+                .clone()
+                .send_all(futures::stream::iter_ok(subscriptions))
+                .map(|_| ())
+                .map_err(|e| println!("failed to subscribe: {}", e)),
+        )
+    }
+
+    /// Checks the names of the zones and the services of every zone. Zones must not share a
+    /// state file or a status topic, one would overwrite what the other saved or published.
+    pub fn check_services(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        for (i, (name, zone)) in self.zones.iter().enumerate() {
+            for (other, earlier) in self.zones[..i].iter() {
+                let shared = if other == name {
+                    "the name"
+                } else if zone.status_topic().is_some()
+                    && zone.status_topic() == earlier.status_topic()
+                {
+                    "the status topic"
+                } else if zone.state_file().is_some()
+                    && zone.state_file().map(StateFile::path)
+                        == earlier.state_file().map(StateFile::path)
+                {
+                    "the state file"
+                } else {
+                    continue;
+                };
+                println!("zones {} and {} share {}", other, name, shared);
+                return Box::new(future::err(()));
+            }
+        }
+
+        let checks = self
+            .zones
+            .iter()
+            .map(|(name, zone)| {
+                let name = name.clone();
+                zone.check_services()
+                    .map_err(move |_| println!("zone {} is not configured correctly", name))
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(checks).map(|_| ()))
+    }
+
+    pub fn resume(&self) {
+        for (_, zone) in self.zones.iter() {
+            zone.resume();
+        }
+    }
+
+    pub fn reconcile(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let reconciled = self
+            .zones
+            .iter()
+            .map(|(_, zone)| zone.reconcile())
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(reconciled).map(|_| ()))
+    }
+
+    /// Every zone gets every message, and ignores the topics it does not know.
+    pub fn handle_msg(&self, msg: OpCode) {
+        if let OpCode::Reconnected = msg {
+            // The broker only sends retained messages on subscribing.
+            tokio::spawn(self.subscribe());
+        }
+        for (_, zone) in self.zones.iter() {
+            zone.handle_msg(msg.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auto_shutdown::{default_plan, ShutdownMessage};
+    use crate::hass::{FakeHass, Instances};
+    use crate::state_machine::State;
+    use futures::future::lazy;
+    use futures::stream::Stream;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn zones_shut_down_independently() {
+        let hass = Instances::new("default", FakeHass::new());
+        let (tx, _rx) = mpsc::channel(16);
+        let zone = |door: &str, amp: &str| {
+            AutoShutdown::new(
+                hass.clone(),
+                door,
+                Duration::from_millis(10),
+                tx.clone(),
+                default_plan(vec![ShutdownMessage::new(amp, "0")], vec![]),
+            )
+        };
+        let zones = Zones::new(tx.clone())
+            .add("workshop", zone("workshop/door", "workshop/amp/set"))
+            .add("lounge", zone("lounge/door", "lounge/amp/set"));
+        assert_eq!(
+            zones.subscriptions(),
+            vec![
+                ("workshop/door".to_string(), QoS::AtLeastOnce),
+                ("lounge/door".to_string(), QoS::AtLeastOnce)
+            ]
+        );
+
+        let zones = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(lazy(move || {
+                zones.handle_msg(OpCode::MessageReceived((
+                    "workshop/door".to_string(),
+                    "1".to_string(),
+                )));
+                tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100))
+                    .map(move |_| zones)
+            }))
+            .unwrap();
+
+        assert_eq!(zones.get("workshop").unwrap().state(), State::Off);
+        assert_eq!(zones.get("lounge").unwrap().state(), State::Idle);
+    }
+
+    #[test]
+    fn shared_topics_are_subscribed_once_after_a_reconnect() {
+        let hass = Instances::new("default", FakeHass::new());
+        let (tx, rx) = mpsc::channel(16);
+        let zone = |qos: QoS| {
+            AutoShutdown::new(
+                hass.clone(),
+                "space/door",
+                Duration::from_millis(10),
+                tx.clone(),
+                default_plan(vec![], vec![]),
+            )
+            .set_subscription_qos(qos)
+        };
+        let zones = Zones::new(tx.clone())
+            .add("workshop", zone(QoS::ExactlyOnce))
+            .add("lounge", zone(QoS::AtMostOnce));
+        drop(tx);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(lazy(move || {
+                zones.handle_msg(OpCode::Reconnected);
+                Ok::<_, ()>(())
+            }))
+            .unwrap();
+        runtime.shutdown_on_idle().wait().unwrap();
+
+        let subscriptions = rx
+            .filter_map(|op| match op {
+                OpCode::Subscribe(subscription) => Some(subscription),
+                _ => None,
+            })
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            subscriptions,
+            vec![("space/door".to_string(), QoS::ExactlyOnce)]
+        );
+    }
+
+    #[test]
+    fn zones_do_not_share_state_files_or_status_topics() {
+        let hass = Instances::new("default", FakeHass::new());
+        let (tx, _rx) = mpsc::channel(16);
+        let zone = |door: &str| {
+            AutoShutdown::new(
+                hass.clone(),
+                door,
+                Duration::from_millis(10),
+                tx.clone(),
+                default_plan(vec![], vec![]),
+            )
+        };
+        let check = |lounge: AutoShutdown<FakeHass>| {
+            Zones::new(tx.clone())
+                .add(
+                    "workshop",
+                    zone("workshop/door")
+                        .set_status_topic("space/shutdown/status")
+                        .set_state_file("/var/lib/shutdown/state.json"),
+                )
+                .add("lounge", lounge)
+                .check_services()
+                .wait()
+        };
+
+        assert_eq!(check(zone("lounge/door")), Ok(()));
+        assert_eq!(
+            check(zone("lounge/door").set_status_topic("space/shutdown/status")),
+            Err(())
+        );
+        assert_eq!(
+            check(zone("lounge/door").set_state_file("/var/lib/shutdown/state.json")),
+            Err(())
+        );
+    }
+}